@@ -0,0 +1,297 @@
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt;
+use std::path::PathBuf;
+use std::str;
+
+/// What to do with module arguments which don't match any field.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnknownArgs {
+    /// Fail the parsing.
+    Reject,
+    /// Report the argument as a warning and go on.
+    Warn,
+    /// Silently skip the argument.
+    Ignore,
+}
+
+/// Error found while parsing module arguments.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PamArgError {
+    /// The argument doesn't match any field.
+    Unknown(String),
+    /// The argument has a missing or malformed value.
+    Invalid { name: String, reason: String },
+}
+
+impl fmt::Display for PamArgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PamArgError::Unknown(arg) => write!(f, "unknown module argument `{}`", arg),
+            PamArgError::Invalid { name, reason } => {
+                write!(f, "invalid module argument `{}`: {}", name, reason)
+            }
+        }
+    }
+}
+
+impl Error for PamArgError {}
+
+/// Raw module argument, as passed by PAM or already decoded.
+pub trait PamArgStr {
+    /// Get the bytes of the argument.
+    fn arg_bytes(&self) -> &[u8];
+}
+
+impl PamArgStr for str {
+    fn arg_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PamArgStr for String {
+    fn arg_bytes(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+impl PamArgStr for CStr {
+    fn arg_bytes(&self) -> &[u8] {
+        self.to_bytes()
+    }
+}
+
+impl<T: PamArgStr + ?Sized> PamArgStr for &T {
+    fn arg_bytes(&self) -> &[u8] {
+        (**self).arg_bytes()
+    }
+}
+
+/// Type of a field parsed by [`pam_args!`](crate::pam_args).
+///
+/// `bool` fields are flags, set with `name` alone, others expect `name=value`.
+pub trait PamArgValue: Sized {
+    /// Parse the value of an argument, `None` if the argument was given without `=`.
+    fn from_arg(value: Option<&str>) -> Result<Self, String>;
+}
+
+impl PamArgValue for bool {
+    fn from_arg(value: Option<&str>) -> Result<bool, String> {
+        match value {
+            None | Some("true") | Some("yes") | Some("on") | Some("1") => Ok(true),
+            Some("false") | Some("no") | Some("off") | Some("0") => Ok(false),
+            Some(v) => Err(format!("expected a boolean, got `{}`", v)),
+        }
+    }
+}
+
+impl<T: PamArgValue> PamArgValue for Option<T> {
+    fn from_arg(value: Option<&str>) -> Result<Option<T>, String> {
+        T::from_arg(value).map(Some)
+    }
+}
+
+macro_rules! from_str_arg_value {
+    ($($ty:ty),*) => {
+        $(
+            impl PamArgValue for $ty {
+                fn from_arg(value: Option<&str>) -> Result<$ty, String> {
+                    value
+                        .ok_or_else(|| "a value is required".to_owned())?
+                        .parse()
+                        .map_err(|e| format!("{}", e))
+                }
+            }
+        )*
+    };
+}
+
+from_str_arg_value!(String, PathBuf, u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64);
+
+/// Module arguments parsed into a struct, usually implemented with [`pam_args!`](crate::pam_args).
+pub trait PamArgs: Default {
+    /// Set the field named `name`. Returns `Ok(false)` if there is no such field.
+    fn set_arg(&mut self, name: &str, value: Option<&str>) -> Result<bool, PamArgError>;
+
+    /// Parse module arguments, calling `warn` on unknown arguments with the
+    /// [`UnknownArgs::Warn`] policy.
+    ///
+    /// Arguments take the form `name` or `name=value`, and may be surrounded by square
+    /// brackets as done in pam.conf(5) for values containing spaces, e.g. `[name=a value]`.
+    /// When a field is given several times the last value is kept.
+    /// Arguments which aren't valid UTF-8 are invalid.
+    fn parse_with<S, F>(args: &[S], unknown: UnknownArgs, mut warn: F) -> Result<Self, PamArgError>
+    where
+        S: PamArgStr,
+        F: FnMut(&PamArgError),
+    {
+        let mut parsed = Self::default();
+        for arg in args {
+            let arg = match str::from_utf8(arg.arg_bytes()) {
+                Ok(arg) => unquote(arg)?,
+                Err(e) => {
+                    return Err(PamArgError::Invalid {
+                        name: String::from_utf8_lossy(arg.arg_bytes()).into_owned(),
+                        reason: e.to_string(),
+                    })
+                }
+            };
+            let mut split = arg.splitn(2, '=');
+            let name = split.next().unwrap_or("");
+            if !parsed.set_arg(name, split.next())? {
+                let err = PamArgError::Unknown(arg.clone());
+                match unknown {
+                    UnknownArgs::Reject => return Err(err),
+                    UnknownArgs::Warn => warn(&err),
+                    UnknownArgs::Ignore => (),
+                }
+            }
+        }
+        Ok(parsed)
+    }
+
+    /// Parse module arguments. See [`parse_with`][Self::parse_with].
+    fn parse<S: PamArgStr>(args: &[S], unknown: UnknownArgs) -> Result<Self, PamArgError> {
+        Self::parse_with(args, unknown, |_| ())
+    }
+}
+
+/// Remove the square brackets around an argument, unescaping `\]`.
+/// Depending on the pam implementation they may or may not be removed already.
+pub(crate) fn unquote(arg: &str) -> Result<String, PamArgError> {
+    if !arg.starts_with('[') {
+        return Ok(arg.to_owned());
+    }
+
+    if arg.len() < 2 || !arg.ends_with(']') || arg.ends_with("\\]") {
+        return Err(PamArgError::Invalid {
+            name: arg.to_owned(),
+            reason: "missing closing bracket".to_owned(),
+        });
+    }
+    Ok(arg[1..arg.len() - 1].replace("\\]", "]"))
+}
+
+/// Define a struct holding the arguments of a PAM module, and implement [`PamArgs`] on it.
+///
+/// Each field is set by the argument with the same name, `bool` fields are flags
+/// and other types are parsed from `name=value` with [`PamArgValue`].
+/// A field may have a default value, otherwise `Default::default()` is used.
+///
+/// # Example
+///
+/// ```rust
+/// #[macro_use] extern crate pamsm;
+/// use pamsm::{PamArgError, PamArgs, UnknownArgs};
+/// use std::ffi::CStr;
+///
+/// pam_args! {
+///     #[derive(Debug)]
+///     pub struct Args {
+///         pub debug: bool,
+///         pub use_first_pass: bool,
+///         pub retries: u32 = 3,
+///         pub query: Option<String>,
+///     }
+/// }
+///
+/// # fn main() {
+/// let args = Args::parse(&["debug", "[query=select * from users]"], UnknownArgs::Reject).unwrap();
+/// assert!(args.debug && !args.use_first_pass);
+/// assert_eq!(args.retries, 3);
+/// assert_eq!(args.query.as_ref().unwrap(), "select * from users");
+///
+/// // The last value is kept, and `\]` stands for `]` inside brackets
+/// let args = Args::parse(&["retries=1", "retries=5", r"[query=a[1\]]"], UnknownArgs::Reject);
+/// let args = args.unwrap();
+/// assert_eq!(args.retries, 5);
+/// assert_eq!(args.query.as_ref().unwrap(), "a[1]");
+///
+/// let err = Args::parse(&["retries=many"], UnknownArgs::Reject).unwrap_err();
+/// assert_eq!(
+///     err.to_string(),
+///     "invalid module argument `retries`: invalid digit found in string"
+/// );
+/// assert_eq!(
+///     Args::parse(&["nullok"], UnknownArgs::Reject).unwrap_err(),
+///     PamArgError::Unknown("nullok".to_owned())
+/// );
+/// assert!(Args::parse(&["nullok"], UnknownArgs::Ignore).is_ok());
+///
+/// let mut warnings = Vec::new();
+/// let args = Args::parse_with(&["nullok", "debug"], UnknownArgs::Warn, |w| {
+///     warnings.push(w.clone())
+/// });
+/// assert!(args.unwrap().debug);
+/// assert_eq!(warnings, vec![PamArgError::Unknown("nullok".to_owned())]);
+///
+/// for arg in &["[", "[debug", r"[query=a\]"] {
+///     let err = Args::parse(&[*arg], UnknownArgs::Reject).unwrap_err();
+///     assert_eq!(
+///         err.to_string(),
+///         format!("invalid module argument `{}`: missing closing bracket", arg)
+///     );
+/// }
+///
+/// // Arguments can be given as passed to the module
+/// let raw = CStr::from_bytes_with_nul(b"query=\xff\0").unwrap();
+/// assert!(Args::parse(&[raw], UnknownArgs::Reject).is_err());
+/// # }
+/// ```
+#[macro_export]
+macro_rules! pam_args {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field_vis:vis $field:ident : $field_ty:ty $(= $default:expr)?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $(
+                $(#[$field_attr])*
+                $field_vis $field: $field_ty,
+            )*
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name {
+                    $( $field: $crate::pam_args!(@default $($default)?), )*
+                }
+            }
+        }
+
+        impl $crate::PamArgs for $name {
+            fn set_arg(
+                &mut self,
+                name: &str,
+                value: Option<&str>,
+            ) -> Result<bool, $crate::PamArgError> {
+                match name {
+                    $(
+                        stringify!($field) => {
+                            self.$field = $crate::PamArgValue::from_arg(value).map_err(|reason| {
+                                $crate::PamArgError::Invalid {
+                                    name: name.to_owned(),
+                                    reason,
+                                }
+                            })?;
+                            Ok(true)
+                        }
+                    )*
+                    _ => Ok(false),
+                }
+            }
+        }
+    };
+    (@default) => {
+        Default::default()
+    };
+    (@default $default:expr) => {
+        $default
+    };
+}
@@ -0,0 +1,321 @@
+//! Implementation of the `pam_sm_*` symbols exported by `pam_module!`.
+
+use args::{unquote, PamArgValue};
+use flags::FromPamFlags;
+#[cfg(feature = "libpam")]
+use libpam::PamLibExt;
+#[cfg(feature = "log")]
+use logger;
+#[cfg(feature = "tracing")]
+use pam::PamResult;
+use pam::{Pam, PamError};
+use pam_types::LogLvl;
+#[cfg(any(feature = "log", feature = "tracing"))]
+use pam_types::PamHandle;
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::time::Instant;
+
+thread_local! {
+    // Whether panics on this thread are caught by `dispatch`
+    static CATCHING: Cell<bool> = const { Cell::new(false) };
+    // Location of the last panic caught by `dispatch`
+    static PANIC_LOCATION: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+#[cfg(any(feature = "log", feature = "tracing"))]
+thread_local! {
+    // Handle of the current call to the module, and whether it got the debug argument
+    static CURRENT_CALL: Cell<Option<(PamHandle, bool)>> = const { Cell::new(None) };
+}
+
+/// Call a `PamServiceModule` method from the exported symbol `name`, on the module instance
+/// returned by `instance`.
+///
+/// Invalid flags are rejected with the error given by `FromPamFlags`.
+/// The calls and their results are logged at the debug level if requested, see `trace_calls`.
+/// If the method panics, the panic is logged to syslog and `on_panic` is returned instead
+/// of unwinding into the application.
+#[doc(hidden)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn dispatch<M, T, I, F>(
+    name: &str,
+    mut pamh: Pam,
+    flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+    on_panic: PamError,
+    instance: I,
+    cb: F,
+) -> c_int
+where
+    M: 'static,
+    T: FromPamFlags + fmt::Debug,
+    I: FnOnce() -> &'static M,
+    F: FnOnce(&M, &mut Pam, T, &[&CStr]) -> PamError,
+{
+    if argc < 0 {
+        return PamError::SERVICE_ERR as c_int;
+    }
+
+    let flags = match T::from_pam_flags(flags) {
+        Ok(flags) => flags,
+        Err(err) => {
+            log(
+                &pamh,
+                LogLvl::ERR,
+                &format!("{} called with invalid flags {:#x}", name, flags),
+            );
+            return err as c_int;
+        }
+    };
+    let args: Vec<&CStr> = (0..(argc as isize))
+        .map(|count| CStr::from_ptr(*argv.offset(count)))
+        .collect();
+
+    install_panic_hook();
+
+    let trace = trace_calls(&args);
+    if trace {
+        let msg = format!("{} called with flags {:?}, args {:?}", name, flags, args);
+        log(&pamh, LogLvl::DEBUG, &msg);
+    }
+    let start = Instant::now();
+
+    let was_catching = CATCHING.with(|c| c.replace(true));
+    // The logging setup is protected too, since installed subscribers run user code
+    let res = panic::catch_unwind(AssertUnwindSafe(|| {
+        #[cfg(any(feature = "log", feature = "tracing"))]
+        let _current_call = CurrentCallGuard::enter(&pamh, &args);
+        // Created first, so that its init function can set its own logger or subscriber
+        let module = instance();
+        #[cfg(feature = "log")]
+        logger::install();
+        #[cfg(feature = "tracing")]
+        let span = call_span(name, &pamh);
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        cb(module, &mut pamh, flags, &args)
+    }));
+    CATCHING.with(|c| c.set(was_catching));
+
+    let res = match res {
+        Ok(err) => err,
+        Err(payload) => {
+            let location = PANIC_LOCATION
+                .with(|l| l.borrow_mut().take())
+                .unwrap_or_else(|| "unknown location".to_owned());
+            let msg = format!(
+                "{} panicked at {}: {}",
+                name,
+                location,
+                panic_message(&*payload)
+            );
+            log(&pamh, LogLvl::CRIT, &msg.replace('\0', "\\0"));
+            on_panic
+        }
+    };
+
+    if trace {
+        let msg = format!(
+            "{} returned {} after {:?}",
+            name,
+            describe(&pamh, res),
+            start.elapsed()
+        );
+        log(&pamh, LogLvl::DEBUG, &msg);
+    }
+    res as c_int
+}
+
+/// Whether to log the calls to the module, with the `debug` argument or when the
+/// PAMSM_DEBUG environment variable is set.
+fn trace_calls(args: &[&CStr]) -> bool {
+    has_debug_arg(args) || debug_env()
+}
+
+/// Like secure_getenv(3), the environment is ignored in setuid and setgid programs such as
+/// su or passwd, since it is controlled by the user calling them.
+fn debug_env() -> bool {
+    const AT_SECURE: c_ulong = 23;
+    if unsafe { getauxval(AT_SECURE) } != 0 {
+        return false;
+    }
+    env::var_os("PAMSM_DEBUG").is_some_and(|v| !v.is_empty() && v != "0")
+}
+
+/// Whether the `debug` argument is set, parsed like a `bool` field of `pam_args!`.
+fn has_debug_arg(args: &[&CStr]) -> bool {
+    let mut debug = false;
+    for arg in args {
+        let arg = match arg.to_str().ok().and_then(|arg| unquote(arg).ok()) {
+            Some(arg) => arg,
+            None => continue,
+        };
+        let mut split = arg.splitn(2, '=');
+        if split.next() == Some("debug") {
+            if let Ok(value) = bool::from_arg(split.next()) {
+                debug = value;
+            }
+        }
+    }
+    debug
+}
+
+/// Record the location of panics happening in `dispatch`, and silence them since they
+/// are logged once caught. Other panics go to the previous hook.
+fn install_panic_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.try_with(|c| c.get()).unwrap_or(false) {
+                let location = info.location().map(|l| l.to_string());
+                let _ = PANIC_LOCATION.try_with(|l| *l.borrow_mut() = location);
+            } else {
+                previous(info);
+            }
+        }));
+    });
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg
+    } else {
+        "Box<dyn Any>"
+    }
+}
+
+#[cfg(feature = "libpam")]
+pub(crate) fn log(pamh: &Pam, lvl: LogLvl, msg: &str) {
+    let _ = pamh.syslog(lvl, msg);
+}
+
+#[cfg(not(feature = "libpam"))]
+pub(crate) fn log(_: &Pam, lvl: LogLvl, msg: &str) {
+    syslog_fallback(lvl, msg);
+}
+
+#[cfg(feature = "libpam")]
+fn describe(pamh: &Pam, err: PamError) -> String {
+    format!("{} ({})", err, err.message(pamh))
+}
+
+#[cfg(not(feature = "libpam"))]
+fn describe(_: &Pam, err: PamError) -> String {
+    err.to_string()
+}
+
+/// Span of a call to the module.
+#[cfg(feature = "tracing")]
+fn call_span(name: &str, pamh: &Pam) -> tracing::Span {
+    let item = |item: PamResult<Option<&CStr>>| {
+        item.ok()
+            .flatten()
+            .map(|s| s.to_string_lossy().into_owned())
+    };
+    tracing::info_span!(
+        "pam",
+        function = name,
+        service = item(pamh.get_service()).as_deref(),
+        user = item(pamh.get_cached_user()).as_deref(),
+        rhost = item(pamh.get_rhost()).as_deref(),
+    )
+}
+
+// Sets the handle of the current call on this thread, until dropped.
+#[cfg(any(feature = "log", feature = "tracing"))]
+struct CurrentCallGuard(Option<(PamHandle, bool)>);
+
+#[cfg(any(feature = "log", feature = "tracing"))]
+impl CurrentCallGuard {
+    fn enter(pamh: &Pam, args: &[&CStr]) -> CurrentCallGuard {
+        let debug = has_debug_arg(args);
+        CurrentCallGuard(CURRENT_CALL.with(|c| c.replace(Some((pamh.0, debug)))))
+    }
+}
+
+#[cfg(any(feature = "log", feature = "tracing"))]
+impl Drop for CurrentCallGuard {
+    fn drop(&mut self) {
+        let _ = CURRENT_CALL.try_with(|c| c.set(self.0));
+    }
+}
+
+/// Whether the module got the `debug` argument, if called from one of its methods.
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub(crate) fn debug_call() -> bool {
+    CURRENT_CALL
+        .try_with(|c| c.get().is_some_and(|(_, debug)| debug))
+        .unwrap_or(false)
+}
+
+/// Send a message with pam_syslog if called from one of the module methods,
+/// with syslog(3) otherwise.
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub(crate) fn syslog_current(lvl: LogLvl, msg: &str) {
+    match CURRENT_CALL.try_with(|c| c.get()).ok().flatten() {
+        Some((handle, _)) => log(&Pam(handle), lvl, msg),
+        None => syslog_fallback(lvl, msg),
+    }
+}
+
+/// Send a message with syslog(3), when there is no handle to use pam_syslog.
+#[cfg(any(not(feature = "libpam"), feature = "log", feature = "tracing"))]
+pub(crate) fn syslog_fallback(lvl: LogLvl, msg: &str) {
+    // LOG_AUTHPRIV
+    const FACILITY: c_int = 10 << 3;
+    let fmt = b"%s\0".as_ptr() as *const c_char;
+    if let Ok(cmsg) = std::ffi::CString::new(msg) {
+        unsafe { syslog(FACILITY | lvl as c_int, fmt, cmsg.as_ptr()) };
+    }
+}
+
+extern "C" {
+    fn getauxval(type_: c_ulong) -> c_ulong;
+}
+
+#[cfg(any(not(feature = "libpam"), feature = "log", feature = "tracing"))]
+extern "C" {
+    fn syslog(priority: c_int, format: *const c_char, ...);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug(args: &[&[u8]]) -> bool {
+        let args: Vec<std::ffi::CString> = args
+            .iter()
+            .map(|arg| std::ffi::CString::new(*arg).unwrap())
+            .collect();
+        let args: Vec<&CStr> = args.iter().map(|arg| arg.as_c_str()).collect();
+        has_debug_arg(&args)
+    }
+
+    #[test]
+    fn debug_arg_is_parsed_like_a_bool() {
+        assert!(debug(&[b"debug"]));
+        assert!(debug(&[b"use_first_pass", b"debug=yes"]));
+        assert!(debug(&[b"[debug=1]"]));
+        assert!(!debug(&[]));
+        assert!(!debug(&[b"debug=off"]));
+        assert!(!debug(&[b"debugger", b"nodebug"]));
+        assert!(!debug(&[b"debug=maybe", b"\xff"]));
+    }
+
+    #[test]
+    fn last_debug_arg_wins() {
+        assert!(!debug(&[b"debug", b"debug=false"]));
+        assert!(debug(&[b"debug=no", b"debug"]));
+    }
+}
@@ -0,0 +1,191 @@
+//! Errors returned by the `PamResultServiceModule` methods.
+
+use args::PamArgError;
+use entry;
+use pam::{Pam, PamError};
+use pam_types::LogLvl;
+use std::error::Error;
+use std::ffi::NulError;
+use std::fmt;
+use std::io;
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+
+pub type PamModuleResult<T> = Result<T, PamModuleError>;
+
+/// Error of a module method, made of the code returned to PAM and the reason of the failure.
+///
+/// The reason is a chain of contexts, added with [`context`][Self::context] or
+/// [`PamResultExt`], and the error which caused the failure if any.
+/// When a [`PamResultServiceModule`](crate::PamResultServiceModule) method returns an error
+/// with a reason, it is logged to syslog before its code is returned to PAM.
+/// An error with the SUCCESS code is returned to PAM as SERVICE_ERR.
+///
+/// Errors from the standard library convert to a default code:
+/// - `io::Error`: PERM_DENIED for `PermissionDenied`, BUF_ERR for `OutOfMemory`,
+///   SYSTEM_ERR otherwise.
+/// - `NulError`, `Utf8Error`, `FromUtf8Error` and [`PamArgError`]: SERVICE_ERR.
+///
+/// ```rust
+/// # extern crate pamsm;
+/// # use pamsm::{PamError, PamModuleResult, PamResultExt};
+/// fn read_config(path: &str) -> PamModuleResult<String> {
+///     let config = std::fs::read_to_string(path).context(format!("reading {}", path))?;
+///     if config.is_empty() {
+///         return Err(PamError::AUTHINFO_UNAVAIL.into());
+///     }
+///     Ok(config)
+/// }
+/// # fn main() {
+/// let err = read_config("/nonexistent").unwrap_err();
+/// assert_eq!(err.code(), PamError::SYSTEM_ERR);
+/// assert_eq!(err.to_string(), "reading /nonexistent");
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct PamModuleError {
+    code: PamError,
+    // Innermost first
+    context: Vec<String>,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl PamModuleError {
+    /// Create an error returning `code` to PAM.
+    pub fn new(code: PamError) -> PamModuleError {
+        PamModuleError {
+            code,
+            context: Vec::new(),
+            source: None,
+        }
+    }
+
+    /// Create an error caused by `source`.
+    pub fn with_source<E>(code: PamError, source: E) -> PamModuleError
+    where
+        E: Into<Box<dyn Error + Send + Sync>>,
+    {
+        PamModuleError {
+            source: Some(source.into()),
+            ..PamModuleError::new(code)
+        }
+    }
+
+    /// Add a context describing what the module was doing when the error happened.
+    pub fn context<C: fmt::Display>(mut self, context: C) -> PamModuleError {
+        self.context.push(context.to_string());
+        self
+    }
+
+    /// Replace the code returned to PAM.
+    pub fn with_code(mut self, code: PamError) -> PamModuleError {
+        self.code = code;
+        self
+    }
+
+    /// Get the code returned to PAM.
+    pub fn code(&self) -> PamError {
+        self.code
+    }
+
+    /// Log the reason of the error to syslog, if any, and get the code to return to PAM.
+    ///
+    /// This is done for the errors returned by [`PamResultServiceModule`] methods,
+    /// [`PamServiceModule`] methods can call it to return the code of a `PamModuleError`.
+    /// An error with the SUCCESS code is always logged, and returned as SERVICE_ERR so that
+    /// PAM doesn't carry on as if the module succeeded.
+    ///
+    /// [`PamResultServiceModule`]: crate::PamResultServiceModule
+    /// [`PamServiceModule`]: crate::PamServiceModule
+    pub fn report(self, pamh: &Pam) -> PamError {
+        let mut reason: Vec<String> = self.context.iter().rev().cloned().collect();
+        let mut source = self.source();
+        while let Some(err) = source {
+            reason.push(err.to_string());
+            source = err.source();
+        }
+        let mut code = self.code;
+        if code == PamError::SUCCESS {
+            reason.push("error returned with the SUCCESS code".to_owned());
+            code = PamError::SERVICE_ERR;
+        }
+        if !reason.is_empty() {
+            let msg = format!("{} ({})", reason.join(": "), code);
+            entry::log(pamh, LogLvl::ERR, &msg.replace('\0', "\\0"));
+        }
+        code
+    }
+}
+
+impl fmt::Display for PamModuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.context.split_last() {
+            Some((last, rest)) => {
+                write!(f, "{}", last)?;
+                for context in rest.iter().rev() {
+                    write!(f, ": {}", context)?;
+                }
+                Ok(())
+            }
+            None => write!(f, "{}", self.code),
+        }
+    }
+}
+
+impl Error for PamModuleError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self.source {
+            Some(ref err) => Some(&**err),
+            None => None,
+        }
+    }
+}
+
+impl From<PamError> for PamModuleError {
+    fn from(code: PamError) -> PamModuleError {
+        PamModuleError::new(code)
+    }
+}
+
+impl From<io::Error> for PamModuleError {
+    fn from(err: io::Error) -> PamModuleError {
+        let code = match err.kind() {
+            io::ErrorKind::PermissionDenied => PamError::PERM_DENIED,
+            io::ErrorKind::OutOfMemory => PamError::BUF_ERR,
+            _ => PamError::SYSTEM_ERR,
+        };
+        PamModuleError::with_source(code, err)
+    }
+}
+
+macro_rules! service_err_from {
+    ($($ty:ty),*) => {
+        $(
+            impl From<$ty> for PamModuleError {
+                fn from(err: $ty) -> PamModuleError {
+                    PamModuleError::with_source(PamError::SERVICE_ERR, err)
+                }
+            }
+        )*
+    };
+}
+
+service_err_from!(NulError, Utf8Error, FromUtf8Error, PamArgError);
+
+/// Add context to the errors converted to [`PamModuleError`].
+pub trait PamResultExt<T> {
+    fn context<C: fmt::Display>(self, context: C) -> PamModuleResult<T>;
+
+    /// Add a context computed only when there is an error.
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> PamModuleResult<T>;
+}
+
+impl<T, E: Into<PamModuleError>> PamResultExt<T> for Result<T, E> {
+    fn context<C: fmt::Display>(self, context: C) -> PamModuleResult<T> {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_context<C: fmt::Display, F: FnOnce() -> C>(self, f: F) -> PamModuleResult<T> {
+        self.map_err(|err| err.into().context(f()))
+    }
+}
@@ -0,0 +1,203 @@
+//! Flags passed to each `PamServiceModule` method.
+//!
+//! They only contain the flags which PAM defines for the method: other flags, and the ones
+//! which aren't combined as PAM requires, are rejected before calling the module.
+
+use pam::PamError;
+use std::os::raw::c_int;
+
+bitflags! {
+    /// Flags of [`PamServiceModule::authenticate`](crate::PamServiceModule::authenticate).
+    pub struct AuthFlags : c_int {
+        const SILENT = 0x8000;
+        const DISALLOW_NULL_AUTHTOK = 0x0001;
+    }
+}
+
+bitflags! {
+    /// Flags of [`PamServiceModule::acct_mgmt`](crate::PamServiceModule::acct_mgmt).
+    pub struct AcctFlags : c_int {
+        const SILENT = 0x8000;
+        const DISALLOW_NULL_AUTHTOK = 0x0001;
+    }
+}
+
+bitflags! {
+    /// Flags of [`PamServiceModule::open_session`](crate::PamServiceModule::open_session)
+    /// and [`PamServiceModule::close_session`](crate::PamServiceModule::close_session).
+    pub struct SessionFlags : c_int {
+        const SILENT = 0x8000;
+    }
+}
+
+bitflags! {
+    /// Flags of [`PamServiceModule::setcred`](crate::PamServiceModule::setcred).
+    ///
+    /// Exactly one of the credential actions is set, see [`action`][Self::action].
+    pub struct CredFlags : c_int {
+        const SILENT = 0x8000;
+        const ESTABLISH_CRED = 0x0002;
+        const DELETE_CRED = 0x0004;
+        const REINITIALIZE_CRED = 0x0008;
+        const REFRESH_CRED = 0x0010;
+    }
+}
+
+bitflags! {
+    /// Flags of [`PamServiceModule::chauthtok`](crate::PamServiceModule::chauthtok).
+    ///
+    /// Exactly one of the phases is set, see [`phase`][Self::phase].
+    pub struct ChauthtokFlags : c_int {
+        const SILENT = 0x8000;
+        const CHANGE_EXPIRED_AUTHTOK = 0x0020;
+        const PRELIM_CHECK = 0x4000;
+        const UPDATE_AUTHTOK = 0x2000;
+    }
+}
+
+/// Credential action requested to [`PamServiceModule::setcred`](crate::PamServiceModule::setcred).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CredAction {
+    Establish,
+    Delete,
+    Reinitialize,
+    Refresh,
+}
+
+/// Phase of [`PamServiceModule::chauthtok`](crate::PamServiceModule::chauthtok).
+///
+/// PAM first calls all the modules of the stack with `Prelim` to check that the token
+/// can be changed, then with `Update` to actually change it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChauthtokPhase {
+    Prelim,
+    Update,
+}
+
+impl CredFlags {
+    const ACTIONS: CredFlags = CredFlags {
+        bits: CredFlags::ESTABLISH_CRED.bits
+            | CredFlags::DELETE_CRED.bits
+            | CredFlags::REINITIALIZE_CRED.bits
+            | CredFlags::REFRESH_CRED.bits,
+    };
+
+    /// Get the requested credential action.
+    pub fn action(&self) -> CredAction {
+        if self.contains(CredFlags::DELETE_CRED) {
+            CredAction::Delete
+        } else if self.contains(CredFlags::REINITIALIZE_CRED) {
+            CredAction::Reinitialize
+        } else if self.contains(CredFlags::REFRESH_CRED) {
+            CredAction::Refresh
+        } else {
+            CredAction::Establish
+        }
+    }
+}
+
+impl ChauthtokFlags {
+    /// Get the current phase of the password change.
+    pub fn phase(&self) -> ChauthtokPhase {
+        if self.contains(ChauthtokFlags::PRELIM_CHECK) {
+            ChauthtokPhase::Prelim
+        } else {
+            ChauthtokPhase::Update
+        }
+    }
+}
+
+/// Conversion from the raw flags passed to a `pam_sm_*` function.
+pub trait FromPamFlags: Sized {
+    /// Get the flags of the function, or the error to return to PAM if they are invalid.
+    fn from_pam_flags(flags: c_int) -> Result<Self, PamError>;
+}
+
+impl FromPamFlags for AuthFlags {
+    fn from_pam_flags(flags: c_int) -> Result<AuthFlags, PamError> {
+        AuthFlags::from_bits(flags).ok_or(PamError::SERVICE_ERR)
+    }
+}
+
+impl FromPamFlags for AcctFlags {
+    fn from_pam_flags(flags: c_int) -> Result<AcctFlags, PamError> {
+        AcctFlags::from_bits(flags).ok_or(PamError::SERVICE_ERR)
+    }
+}
+
+impl FromPamFlags for SessionFlags {
+    fn from_pam_flags(flags: c_int) -> Result<SessionFlags, PamError> {
+        SessionFlags::from_bits(flags).ok_or(PamError::SERVICE_ERR)
+    }
+}
+
+impl FromPamFlags for CredFlags {
+    fn from_pam_flags(flags: c_int) -> Result<CredFlags, PamError> {
+        let flags = CredFlags::from_bits(flags).ok_or(PamError::SERVICE_ERR)?;
+        match (flags & CredFlags::ACTIONS).bits().count_ones() {
+            // Same default as libpam
+            0 => Ok(flags | CredFlags::ESTABLISH_CRED),
+            1 => Ok(flags),
+            _ => Err(PamError::SERVICE_ERR),
+        }
+    }
+}
+
+impl FromPamFlags for ChauthtokFlags {
+    // Same error as libpam when the application sets the phase
+    fn from_pam_flags(flags: c_int) -> Result<ChauthtokFlags, PamError> {
+        let flags = ChauthtokFlags::from_bits(flags).ok_or(PamError::SYSTEM_ERR)?;
+        let prelim = flags.contains(ChauthtokFlags::PRELIM_CHECK);
+        let update = flags.contains(ChauthtokFlags::UPDATE_AUTHTOK);
+        if prelim != update {
+            Ok(flags)
+        } else {
+            Err(PamError::SYSTEM_ERR)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pam::PamError::{SERVICE_ERR, SYSTEM_ERR};
+
+    #[test]
+    fn cred_action_defaults_to_establish() {
+        let flags = CredFlags::from_pam_flags(0).unwrap();
+        assert_eq!(flags, CredFlags::ESTABLISH_CRED);
+        let flags = CredFlags::from_pam_flags(0x8000).unwrap();
+        assert_eq!(flags, CredFlags::SILENT | CredFlags::ESTABLISH_CRED);
+        assert_eq!(flags.action(), CredAction::Establish);
+    }
+
+    #[test]
+    fn cred_requires_at_most_one_action() {
+        let flags = CredFlags::from_pam_flags(0x0004).unwrap();
+        assert_eq!(flags.action(), CredAction::Delete);
+        assert_eq!(CredFlags::from_pam_flags(0x0006), Err(SERVICE_ERR));
+        assert_eq!(CredFlags::from_pam_flags(0x001e), Err(SERVICE_ERR));
+    }
+
+    #[test]
+    fn chauthtok_requires_one_phase() {
+        let flags = ChauthtokFlags::from_pam_flags(0x4000).unwrap();
+        assert_eq!(flags.phase(), ChauthtokPhase::Prelim);
+        let flags = ChauthtokFlags::from_pam_flags(0x2020).unwrap();
+        assert_eq!(flags.phase(), ChauthtokPhase::Update);
+        assert!(flags.contains(ChauthtokFlags::CHANGE_EXPIRED_AUTHTOK));
+        assert_eq!(ChauthtokFlags::from_pam_flags(0), Err(SYSTEM_ERR));
+        assert_eq!(ChauthtokFlags::from_pam_flags(0x6000), Err(SYSTEM_ERR));
+    }
+
+    #[test]
+    fn unknown_bits_are_rejected() {
+        assert_eq!(AuthFlags::from_pam_flags(0x0002), Err(SERVICE_ERR));
+        assert_eq!(AcctFlags::from_pam_flags(0x0010_0000), Err(SERVICE_ERR));
+        assert_eq!(SessionFlags::from_pam_flags(0x0001), Err(SERVICE_ERR));
+        assert_eq!(CredFlags::from_pam_flags(0x0022), Err(SERVICE_ERR));
+        assert_eq!(ChauthtokFlags::from_pam_flags(0x4001), Err(SYSTEM_ERR));
+        let flags = AuthFlags::from_pam_flags(0x8001).unwrap();
+        assert_eq!(flags, AuthFlags::SILENT | AuthFlags::DISALLOW_NULL_AUTHTOK);
+    }
+}
@@ -8,12 +8,14 @@
 //! #[macro_use] extern crate pamsm;
 //! extern crate time;
 //!
-//! use pamsm::{PamServiceModule, Pam, PamFlags, PamError};
+//! use pamsm::{AuthFlags, PamServiceModule, Pam, PamError};
+//! use std::ffi::CStr;
 //!
+//! #[derive(Default)]
 //! struct PamTime;
 //!
 //! impl PamServiceModule for PamTime {
-//!     fn authenticate(pamh: Pam, _: PamFlags, args: Vec<String>) -> PamError {
+//!     fn authenticate(&self, pamh: &mut Pam, _: AuthFlags, args: &[&CStr]) -> PamError {
 //!         let hour = time::OffsetDateTime::now_utc().hour();
 //!         if hour != 4 {
 //!             // Only allow authentication when it's 4 AM
@@ -28,15 +30,48 @@
 //! ```
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "serde")]
+extern crate serde_json;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "tracing")]
+extern crate tracing_subscriber;
 
+mod args;
+mod entry;
+mod error;
+mod flags;
 #[cfg(feature = "libpam")]
 mod libpam;
+#[cfg(feature = "log")]
+mod logger;
 mod pam;
 mod pam_types;
+mod secret;
+#[cfg(feature = "tracing")]
+mod syslog_layer;
 
-pub use pam::{Pam, PamError, PamFlags, PamSendRef, PamServiceModule};
+pub use args::{PamArgError, PamArgStr, PamArgValue, PamArgs, UnknownArgs};
+#[doc(hidden)]
+pub use entry::dispatch as __dispatch;
+pub use error::{PamModuleError, PamModuleResult, PamResultExt};
+pub use flags::{
+    AcctFlags, AuthFlags, ChauthtokFlags, ChauthtokPhase, CredAction, CredFlags, SessionFlags,
+};
+pub use pam::{
+    Pam, PamError, PamFlags, PamResult, PamResultServiceModule, PamSendRef, PamServiceModule,
+};
+pub use secret::{Secret, SecretStr};
 
 #[cfg(feature = "libpam")]
-pub use libpam::{PamCleanupCb, PamData, PamLibExt, PamResult};
+pub use libpam::{PamCleanupCb, PamConvResponse, PamData, PamEnvList, PamItemStr, PamLibExt};
+#[cfg(feature = "log")]
+pub use logger::PamLogger;
 #[cfg(feature = "libpam")]
-pub use pam_types::{LogLvl, PamMsgStyle};
+pub use pam_types::{LogLvl, PamFailDelayFn, PamMsgStyle, PamXAuthData, PAM_MAX_NUM_MSG};
+#[cfg(feature = "tracing")]
+pub use syslog_layer::PamSyslogLayer;
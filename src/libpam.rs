@@ -1,18 +1,32 @@
 #![allow(dead_code)]
 
-use pam::{Pam, PamError, PamFlags};
-use pam_types::{LogLvl, PamConv, PamHandle, PamItemType, PamMessage, PamMsgStyle, PamResponse};
+use args::{PamArgStr, PamArgs, UnknownArgs};
+#[cfg(feature = "serde")]
+use error::PamModuleError;
+use error::{PamModuleResult, PamResultExt};
+use pam::{Pam, PamError, PamFlags, PamResult};
+use pam_types::{
+    LogLvl, PamConv, PamFailDelayFn, PamHandle, PamItemType, PamMessage, PamMsgStyle, PamResponse,
+    PamXAuthData, PamXAuthDataRaw, PAM_MAX_NUM_MSG,
+};
+use secret::{zeroize, SecretStr};
+use std::any::TypeId;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::ffi::{CStr, CString, NulError};
+use std::fmt;
 use std::ops::Deref;
 use std::option::Option;
-use std::os::raw::{c_char, c_int, c_void};
-use std::ptr;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr::{self, NonNull};
+use std::time::Duration;
 
-pub type PamResult<T> = Result<T, PamError>;
 /// Prototype of the callback used with [`PamLibExt::send_bytes`]
-pub type PamCleanupCb = fn(&Vec<u8>, Pam, PamFlags, PamError);
+///
+/// The callback is called once, when pam cleans up the data, and takes ownership of the bytes.
+/// Since it is a closure it can capture its own configuration.
+pub type PamCleanupCb = Box<dyn FnOnce(Vec<u8>, &Pam, PamFlags, PamError) + Send>;
 
-#[derive(Clone)]
 struct PamByteData {
     cb: Option<PamCleanupCb>,
     data: Vec<u8>,
@@ -29,12 +43,12 @@ struct PamByteData {
 /// struct Token([u8; 32]);
 ///
 /// impl PamData for Token {
-///     fn cleanup(&self, _pam: Pam, flags: PamFlags, status: PamError) {
+///     fn cleanup(&self, _pam: &Pam, flags: PamFlags, status: PamError) {
 ///         if !flags.contains(PamFlags::DATA_REPLACE) && status == PamError::SUCCESS {
 ///             match write(".token.bin", self.0) {
 ///                 Ok(_) => (),
 ///                 Err(err) => {
-///                     if !flags.contains(PamFlags::SILENT) {
+///                     if !flags.contains(PamFlags::DATA_SILENT) {
 ///                         println!("Error persisting token : {:?}", err);
 ///                     }
 ///                 }
@@ -46,13 +60,55 @@ struct PamByteData {
 pub trait PamData {
     /// The cleanup method will be called before the data is dropped by pam.
     /// See `pam_set_data (3)`
-    fn cleanup(&self, _pam: Pam, _flags: PamFlags, _status: PamError) {}
+    fn cleanup(&self, _pam: &Pam, _flags: PamFlags, _status: PamError) {}
+}
+
+/// Prefix of the names under which [`PamLibExt::send_data`] stores the data, so that data
+/// stored by other means can't be read as typed data.
+const TYPED_DATA_PREFIX: &str = "pamsm/data/";
+/// Identifies the data stored by this crate with [`PamLibExt::send_data`]
+const TYPED_DATA_MAGIC: u64 = u64::from_be_bytes(*b"pamsm\0td");
+
+/// Tag stored in front of the data sent to pam, used to check its type on retrieval.
+#[repr(C)]
+struct TypedDataHeader {
+    magic: u64,
+    type_id: TypeId,
+}
+
+#[repr(C)]
+struct TypedData<T> {
+    header: TypedDataHeader,
+    // None once the data has been taken back with take_data
+    data: Option<T>,
 }
 
-impl PamData for PamByteData {
-    fn cleanup(&self, pam: Pam, flags: PamFlags, status: PamError) {
-        if let Some(cb) = self.cb {
-            (cb)(&self.data, pam, flags, status);
+/// Prefix of the names under which [`PamLibExt::send_serialized`] stores the data
+#[cfg(feature = "serde")]
+const SERIALIZED_DATA_PREFIX: &str = "pamsm/serialized/";
+/// Identifies the data stored with [`PamLibExt::send_serialized`]
+#[cfg(feature = "serde")]
+const SERIALIZED_DATA_MAGIC: [u8; 8] = *b"pamsm\0sd";
+/// Version of the encoding used by [`PamLibExt::send_serialized`]
+#[cfg(feature = "serde")]
+const SERIALIZED_DATA_VERSION: u32 = 1;
+
+/// Header of the buffers stored by [`PamLibExt::send_serialized`], followed by `len` bytes
+/// of JSON. Its layout doesn't depend on the compiler, so that separately compiled
+/// modules can read it.
+#[cfg(feature = "serde")]
+#[repr(C)]
+struct SerializedDataHeader {
+    magic: [u8; 8],
+    version: u32,
+    reserved: u32,
+    len: u64,
+}
+
+impl<T: PamData> PamData for TypedData<T> {
+    fn cleanup(&self, pam: &Pam, flags: PamFlags, status: PamError) {
+        if let Some(ref data) = self.data {
+            data.cleanup(pam, flags, status)
         }
     }
 }
@@ -62,12 +118,145 @@ impl<T: PamData, U> PamData for U
 where
     U: Deref<Target = T>,
 {
-    fn cleanup(&self, pam: Pam, flags: PamFlags, status: PamError) {
+    fn cleanup(&self, pam: &Pam, flags: PamFlags, status: PamError) {
         T::cleanup(self, pam, flags, status)
     }
 }
 
+/// Response to a conversation message, returned by [`PamLibExt::conv`]
+/// and [`PamLibExt::conv_multi`].
+///
+/// The response buffer allocated by the application is owned by this value.
+/// It is overwritten with zeros then freed when dropped, so that secrets typed
+/// by the user don't linger in memory.
+///
+/// Since the response may be a password, its text is accessed through [`SecretStr`].
+pub struct PamConvResponse(NonNull<c_char>);
+
+// The response buffer is exclusively owned and never mutated before being dropped.
+unsafe impl Send for PamConvResponse {}
+unsafe impl Sync for PamConvResponse {}
+
+impl Deref for PamConvResponse {
+    type Target = SecretStr;
+
+    fn deref(&self) -> &SecretStr {
+        SecretStr::new(unsafe { CStr::from_ptr(self.0.as_ptr()) })
+    }
+}
+
+impl fmt::Debug for PamConvResponse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("PamConvResponse(..)")
+    }
+}
+
+impl Drop for PamConvResponse {
+    fn drop(&mut self) {
+        let len = self.expose().to_bytes().len();
+        unsafe {
+            zeroize(std::slice::from_raw_parts_mut(
+                self.0.as_ptr() as *mut u8,
+                len,
+            ));
+            free(self.0.as_ptr() as *mut c_void);
+        }
+    }
+}
+
+/// Owned copy of the pam environment list, returned by [`PamLibExt::getenvlist`].
+///
+/// This iterates over the `(name, value)` pairs of the environment. The underlying
+/// array is overwritten with zeros and freed as it is consumed, or when dropped.
+/// ```rust
+/// # use pamsm::{Pam, PamLibExt, PamModuleResult};
+/// # fn wrapper(pamh: &mut Pam) -> PamModuleResult<()> {
+/// // Remove the locale settings
+/// let env = pamh.getenvlist()?.into_map();
+/// pamh.unsetenv_many(env.keys().filter(|name| name.starts_with("LC_")))?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct PamEnvList {
+    list: NonNull<*mut c_char>,
+    pos: usize,
+}
+
+// The array and its entries are exclusively owned.
+unsafe impl Send for PamEnvList {}
+
+impl PamEnvList {
+    /// Collect the environment into a map, replacing any invalid UTF-8 sequence
+    /// with `U+FFFD REPLACEMENT CHARACTER`.
+    pub fn into_map(self) -> BTreeMap<String, String> {
+        self.map(|(name, value)| {
+            (
+                name.to_string_lossy().into_owned(),
+                value.to_string_lossy().into_owned(),
+            )
+        })
+        .collect()
+    }
+}
+
+impl Iterator for PamEnvList {
+    type Item = (CString, CString);
+
+    fn next(&mut self) -> Option<(CString, CString)> {
+        let entry = unsafe { *self.list.as_ptr().add(self.pos) };
+        if entry.is_null() {
+            return None;
+        }
+        self.pos += 1;
+
+        let bytes = unsafe { CStr::from_ptr(entry) }.to_bytes();
+        let split = bytes.iter().position(|&b| b == b'=').unwrap_or(bytes.len());
+        // The entry doesn't contain any null byte, neither do its parts
+        let (name, value) = unsafe {
+            (
+                CString::from_vec_unchecked(bytes[..split].to_vec()),
+                CString::from_vec_unchecked(bytes.get(split + 1..).unwrap_or(&[]).to_vec()),
+            )
+        };
+        unsafe { free_env_entry(entry) };
+        Some((name, value))
+    }
+}
+
+impl Drop for PamEnvList {
+    fn drop(&mut self) {
+        for _ in self.by_ref() {}
+        unsafe { free(self.list.as_ptr() as *mut c_void) };
+    }
+}
+
+unsafe fn free_env_entry(entry: *mut c_char) {
+    let len = CStr::from_ptr(entry).to_bytes().len();
+    zeroize(std::slice::from_raw_parts_mut(entry as *mut u8, len));
+    free(entry as *mut c_void);
+}
+
+/// Check that `name` can be used as a variable name with `pam_putenv (3)`
+fn check_env_name(name: &str) -> PamResult<()> {
+    if name.is_empty() || name.contains('=') {
+        Err(PamError::BAD_ITEM)
+    } else {
+        Ok(())
+    }
+}
+
 impl PamError {
+    /// Get the description of the error given by `pam_strerror`, e.g. "Authentication failure".
+    pub fn message(&self, pamh: &Pam) -> String {
+        let msg = unsafe { pam_strerror(pamh.0, *self as c_int) };
+        if msg.is_null() {
+            return self.to_string();
+        }
+        unsafe { CStr::from_ptr(msg) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
     fn to_result<T>(self, ok: T) -> PamResult<T> {
         if self == PamError::SUCCESS {
             Ok(ok)
@@ -77,6 +266,47 @@ impl PamError {
     }
 }
 
+/// String passed to the item setters of [`PamLibExt`].
+///
+/// `str` and `String` are converted to a C string and must not contain any null byte,
+/// `CStr` and `CString` are passed as is, so items don't have to be valid UTF-8.
+/// ```rust
+/// # use pamsm::{Pam, PamLibExt, PamModuleResult};
+/// # use std::ffi::CStr;
+/// # fn wrapper(pamh: &mut Pam, raw_host: &CStr) -> PamModuleResult<()> {
+/// pamh.set_ruser("root")?;
+/// pamh.set_rhost(raw_host)?;
+/// # Ok(())
+/// # }
+/// ```
+pub trait PamItemStr {
+    fn to_item_cstr(&self) -> Result<Cow<'_, CStr>, NulError>;
+}
+
+impl PamItemStr for str {
+    fn to_item_cstr(&self) -> Result<Cow<'_, CStr>, NulError> {
+        CString::new(self).map(Cow::Owned)
+    }
+}
+
+impl PamItemStr for String {
+    fn to_item_cstr(&self) -> Result<Cow<'_, CStr>, NulError> {
+        self.as_str().to_item_cstr()
+    }
+}
+
+impl PamItemStr for CStr {
+    fn to_item_cstr(&self) -> Result<Cow<'_, CStr>, NulError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+impl PamItemStr for CString {
+    fn to_item_cstr(&self) -> Result<Cow<'_, CStr>, NulError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
 /// This contains a private marker trait, used to seal private traits.
 mod private {
     pub trait Sealed {}
@@ -101,45 +331,212 @@ impl Pam {
             r.to_result(Some(unsafe { CStr::from_ptr(raw_item as *const c_char) }))
         }
     }
+
+    // Store `data` under `module_name` along with its type tag
+    fn set_typed_data<T: Send + 'static>(
+        &mut self,
+        module_name: &str,
+        data: T,
+        cleanup: PamDataCleanupCb,
+    ) -> PamModuleResult<()> {
+        let cname = data_name(TYPED_DATA_PREFIX, module_name)?;
+        // The data has to be allocated on the heap because it will outlive the call stack.
+        let data_copy = Box::new(TypedData {
+            header: TypedDataHeader {
+                magic: TYPED_DATA_MAGIC,
+                type_id: TypeId::of::<T>(),
+            },
+            data: Some(data),
+        });
+        let raw = Box::into_raw(data_copy);
+        let r = unsafe {
+            PamError::new(pam_set_data(
+                self.0,
+                cname.as_ptr(),
+                raw as *mut c_void,
+                Some(cleanup),
+            ))
+        };
+        if r != PamError::SUCCESS {
+            // pam didn't take the data, it is dropped without calling the cleanup callback
+            drop(unsafe { Box::from_raw(raw) });
+            return Err(r.into());
+        }
+        Ok(())
+    }
+
+    // Remove the data stored under `module_name` in the namespace given by `prefix`
+    fn remove_named_data(&mut self, prefix: &str, module_name: &str) -> PamModuleResult<()> {
+        let cname = data_name(prefix, module_name)?;
+        let mut data_ptr: *const c_void = ptr::null();
+        let r = unsafe { PamError::new(pam_get_data(self.0, cname.as_ptr(), &mut data_ptr)) };
+        if r != PamError::SUCCESS || data_ptr.is_null() {
+            return Err(PamError::NO_MODULE_DATA.into());
+        }
+
+        // pam calls the cleanup callback of the previous data before storing the null pointer.
+        unsafe {
+            PamError::new(pam_set_data(self.0, cname.as_ptr(), ptr::null_mut(), None))
+                .to_result(())?;
+        }
+        Ok(())
+    }
+
+    // Get a pointer to the data stored under `module_name` with send_data<T>
+    fn get_typed_data<T: 'static>(&self, module_name: &str) -> PamModuleResult<*mut TypedData<T>> {
+        let cname = data_name(TYPED_DATA_PREFIX, module_name)?;
+        let mut data_ptr: *const c_void = ptr::null();
+        let r = unsafe { PamError::new(pam_get_data(self.0, cname.as_ptr(), &mut data_ptr)) };
+        if r != PamError::SUCCESS {
+            return Err(r.into());
+        }
+
+        let header = match unsafe { (data_ptr as *const TypedDataHeader).as_ref() } {
+            Some(header) => header,
+            None => return Err(PamError::NO_MODULE_DATA.into()),
+        };
+        if header.magic != TYPED_DATA_MAGIC || header.type_id != TypeId::of::<T>() {
+            return Err(PamError::NO_MODULE_DATA.into());
+        }
+        Ok(data_ptr as *mut TypedData<T>)
+    }
+
+    // End users should call the item specific methods
+    fn set_str_item<S: PamItemStr + ?Sized>(
+        &mut self,
+        item_type: PamItemType,
+        item: &S,
+    ) -> PamModuleResult<()> {
+        let citem = item
+            .to_item_cstr()
+            .with_context(|| format!("invalid PAM_{:?} item", item_type))?;
+        // pam copies string items, the CString can be dropped afterwards
+        unsafe { set_item(self.0, item_type, citem.as_ptr() as *const c_void)? };
+        Ok(())
+    }
 }
 
 /// Extension trait over `Pam`, usually provided by the `libpam` shared library.
+///
+/// The methods taking strings return a [`PamModuleError`](crate::PamModuleError) naming the
+/// invalid argument. It is logged when returned from a
+/// [`PamResultServiceModule`](crate::PamResultServiceModule) method, modules implementing
+/// [`PamServiceModule`](crate::PamServiceModule) can log it with
+/// [`PamModuleError::report`](crate::PamModuleError::report).
 pub trait PamLibExt: private::Sealed {
     /// Get the username. If the PAM_USER item is not set, this function
     /// prompts for a username (like get_authtok).
     /// Returns PamError::SERVICE_ERR if the prompt contains any null byte
-    fn get_user(&self, prompt: Option<&str>) -> PamResult<Option<&CStr>>;
+    fn get_user(&self, prompt: Option<&str>) -> PamModuleResult<Option<&CStr>>;
 
     /// Get the username, i.e. the PAM_USER item. If it's not set return None.
     fn get_cached_user(&self) -> PamResult<Option<&CStr>>;
 
     /// Get the cached authentication token.
-    fn get_cached_authtok(&self) -> PamResult<Option<&CStr>>;
+    fn get_cached_authtok(&self) -> PamResult<Option<&SecretStr>>;
 
     /// Get the cached old authentication token.
-    fn get_cached_oldauthtok(&self) -> PamResult<Option<&CStr>>;
+    fn get_cached_oldauthtok(&self) -> PamResult<Option<&SecretStr>>;
 
     /// Get the cached authentication token or prompt the user for one if there isn't any.
     /// Returns PamError::SERVICE_ERR if the prompt contains any null byte
-    fn get_authtok(&self, prompt: Option<&str>) -> PamResult<Option<&CStr>>;
+    fn get_authtok(&self, prompt: Option<&str>) -> PamModuleResult<Option<&SecretStr>>;
+
+    /// Set the authentication token, i.e. the PAM_AUTHTOK item.
+    fn set_authtok(&mut self, authtok: &SecretStr) -> PamResult<()>;
+
+    /// Set the old authentication token, i.e. the PAM_OLDAUTHTOK item.
+    fn set_oldauthtok(&mut self, oldauthtok: &SecretStr) -> PamResult<()>;
+
+    /// Set the username, i.e. the PAM_USER item.
+    /// Returns PamError::SERVICE_ERR if the username contains any null byte
+    fn set_user<S: PamItemStr + ?Sized>(&mut self, user: &S) -> PamModuleResult<()>;
 
-    fn set_authtok(&self, authtok: &CString) -> PamResult<()>;
+    /// Get the terminal name.
+    fn get_tty(&self) -> PamResult<Option<&CStr>>;
+
+    /// Set the terminal name, i.e. the PAM_TTY item.
+    /// Returns PamError::SERVICE_ERR if the terminal name contains any null byte
+    fn set_tty<S: PamItemStr + ?Sized>(&mut self, tty: &S) -> PamModuleResult<()>;
 
     /// Get the remote hostname.
     fn get_rhost(&self) -> PamResult<Option<&CStr>>;
 
+    /// Set the remote hostname, i.e. the PAM_RHOST item.
+    /// Returns PamError::SERVICE_ERR if the hostname contains any null byte
+    fn set_rhost<S: PamItemStr + ?Sized>(&mut self, rhost: &S) -> PamModuleResult<()>;
+
     /// Get the remote username.
     fn get_ruser(&self) -> PamResult<Option<&CStr>>;
 
+    /// Set the remote username, i.e. the PAM_RUSER item.
+    /// Returns PamError::SERVICE_ERR if the username contains any null byte
+    fn set_ruser<S: PamItemStr + ?Sized>(&mut self, ruser: &S) -> PamModuleResult<()>;
+
+    /// Get the prompt used by `get_user` when the username is unknown.
+    fn get_user_prompt(&self) -> PamResult<Option<&CStr>>;
+
+    /// Set the prompt used by `get_user`, i.e. the PAM_USER_PROMPT item.
+    /// Returns PamError::SERVICE_ERR if the prompt contains any null byte
+    fn set_user_prompt<S: PamItemStr + ?Sized>(&mut self, prompt: &S) -> PamModuleResult<()>;
+
+    /// Get the X display name.
+    fn get_xdisplay(&self) -> PamResult<Option<&CStr>>;
+
+    /// Set the X display name, i.e. the PAM_XDISPLAY item.
+    /// Returns PamError::SERVICE_ERR if the display name contains any null byte
+    fn set_xdisplay<S: PamItemStr + ?Sized>(&mut self, xdisplay: &S) -> PamModuleResult<()>;
+
+    /// Get the authentication token type, used in the password prompts of `get_authtok`.
+    fn get_authtok_type(&self) -> PamResult<Option<&CStr>>;
+
+    /// Set the authentication token type, i.e. the PAM_AUTHTOK_TYPE item.
+    /// Returns PamError::SERVICE_ERR if the type contains any null byte
+    fn set_authtok_type<S: PamItemStr + ?Sized>(&mut self, authtok_type: &S)
+        -> PamModuleResult<()>;
+
+    /// Get the X server authentication data.
+    fn get_xauthdata(&self) -> PamResult<Option<PamXAuthData<'_>>>;
+
+    /// Set the X server authentication data, i.e. the PAM_XAUTHDATA item.
+    /// Returns PamError::SERVICE_ERR if the name contains any null byte, and
+    /// PamError::BUF_ERR if the name or the data is too large.
+    fn set_xauthdata(&mut self, xauthdata: &PamXAuthData) -> PamModuleResult<()>;
+
     /// Get the service name.
     fn get_service(&self) -> PamResult<Option<&CStr>>;
 
     /// Prompt the user for custom input.
     /// Returns PamError::SERVICE_ERR if the prompt contains any null byte
-    fn conv(&self, prompt: Option<&str>, style: PamMsgStyle) -> PamResult<Option<&CStr>>;
+    fn conv(
+        &self,
+        prompt: Option<&str>,
+        style: PamMsgStyle,
+    ) -> PamModuleResult<Option<PamConvResponse>>;
+
+    /// Send several messages to the user in a single call to the conversation function,
+    /// for instance an informative text followed by a prompt.
+    ///
+    /// The result contains one response per message, in the same order as `msgs`.
+    /// Messages which don't expect an answer usually get a `None` response.
+    ///
+    /// Returns PamError::CONV_ERR if more than [`PAM_MAX_NUM_MSG`] messages are passed,
+    /// and PamError::SERVICE_ERR if a message contains any null byte
+    fn conv_multi(
+        &self,
+        msgs: &[(PamMsgStyle, &str)],
+    ) -> PamModuleResult<Vec<Option<PamConvResponse>>>;
+
+    /// Request a minimum delay before the application gets control back after a failure
+    /// of the stack. See pam_fail_delay(3).
+    /// Delays larger than `c_uint::MAX` microseconds are truncated.
+    fn fail_delay(&self, delay: Duration) -> PamResult<()>;
+
+    /// Get the delay function installed by the application, i.e. the PAM_FAIL_DELAY item.
+    fn get_fail_delay_fn(&self) -> PamResult<Option<PamFailDelayFn>>;
 
     /// Get a variable from the pam environment list.
-    fn getenv(&self, name: &str) -> PamResult<Option<&CStr>>;
+    fn getenv(&self, name: &str) -> PamModuleResult<Option<&CStr>>;
 
     /// Put a variable in the pam environment list.
     /// `name_value` takes for form documented in pam_putent(3) :
@@ -147,28 +544,58 @@ pub trait PamLibExt: private::Sealed {
     /// - `NAME=value` will set variable `NAME` to value `value`
     /// - `NAME=` will set variable `NAME` to an empty value
     /// - `NAME` will unset the variable `NAME`
-    fn putenv(&self, name_value: &str) -> PamResult<()>;
+    fn putenv(&mut self, name_value: &str) -> PamModuleResult<()>;
+
+    /// Get a copy of the whole pam environment list. See pam_getenvlist(3).
+    /// Returns PamError::BUF_ERR if pam fails to allocate the copy.
+    fn getenvlist(&self) -> PamResult<PamEnvList>;
+
+    /// Set several variables in the pam environment list.
+    ///
+    /// Stops at the first error, in which case the previous variables are still set.
+    /// Returns PamError::BAD_ITEM if a name is empty or contains `=`,
+    /// and PamError::SERVICE_ERR if a name or a value contains any null byte
+    fn putenv_many<I, K, V>(&mut self, vars: I) -> PamModuleResult<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>;
+
+    /// Remove several variables from the pam environment list.
+    /// Variables which aren't set are ignored.
+    ///
+    /// Stops at the first error, in which case the previous variables are still removed.
+    /// Returns PamError::BAD_ITEM if a name is empty or contains `=`,
+    /// and PamError::SERVICE_ERR if a name contains any null byte
+    fn unsetenv_many<I, K>(&mut self, names: I) -> PamModuleResult<()>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>;
 
     /// Send data to be stored by the pam library under the name `module_name`.
-    /// The data can then be retrieved from a different
-    /// callback in this module, or even by a different module
+    /// The data can then be retrieved from a different callback in this module
     /// using [`retrieve_data<T>`][Self::retrieve_data].
     ///
+    /// The data is stored along with a tag identifying its type, so that it can't be
+    /// retrieved as a different type. The tag is the [`TypeId`][std::any::TypeId] of the type,
+    /// which may or may not match across separately compiled modules, so use
+    /// [`send_serialized`][Self::send_serialized] to share data with other modules.
+    ///
+    /// The name is kept apart from the names used with `pam_set_data (3)` by other means,
+    /// such as modules written in C.
+    ///
     /// When this method is called a second time with the same `module_name`, the method
     /// [`PamData::cleanup`] is called on the data previously stored.
-    /// The same happens when the application calls `pam_end (3)`
+    /// The same happens when the application calls `pam_end (3)`.
+    /// If pam fails to store the data, it is dropped without calling [`PamData::cleanup`].
     ///
     /// If your data can be converted into / from [`Vec<u8>`][std::vec::Vec]
     /// you should consider using the [`send_bytes`][Self::send_bytes] method instead.
-    ///
-    /// # Safety
-    /// This method should not be used if the [`send_bytes`][Self::send_bytes] method is also used
-    /// with the same `module_name`.
-    unsafe fn send_data<T: PamData + Clone + Send>(
-        &self,
+    fn send_data<T: PamData + Send + 'static>(
+        &mut self,
         module_name: &str,
         data: T,
-    ) -> PamResult<()>;
+    ) -> PamModuleResult<()>;
 
     /// Retrieve data previously stored with [`send_data<T>`][Self::send_data].
     ///
@@ -176,30 +603,157 @@ pub trait PamLibExt: private::Sealed {
     /// which differs from the behavior of the underlying `pam_get_data (3)` function.
     ///
     /// If you want to share the data instead you can wrap it in [`Arc`][std::sync::Arc].
-    /// # Safety
-    /// The type parameter `T` must be the same as the one used in
-    /// [`send_data<T>`][Self::send_data] with the name `module_name`.
     ///
-    /// If the data was stored with [`send_bytes`][Self::send_bytes] you must use
-    /// [`retrieve_bytes`][Self::retrieve_bytes] instead.
-    unsafe fn retrieve_data<T: PamData + Clone + Send>(&self, module_name: &str) -> PamResult<T>;
+    /// Returns PamError::NO_MODULE_DATA if there is no data under `module_name`,
+    /// or if it wasn't stored with the type `T`.
+    fn retrieve_data<T: PamData + Clone + Send + 'static>(
+        &self,
+        module_name: &str,
+    ) -> PamModuleResult<T>;
+
+    /// Borrow data previously stored with [`send_data<T>`][Self::send_data], without copying it.
+    ///
+    /// Returns the same errors as [`retrieve_data<T>`][Self::retrieve_data].
+    fn retrieve_data_ref<T: PamData + Send + 'static>(
+        &self,
+        module_name: &str,
+    ) -> PamModuleResult<&T>;
+
+    /// Mutably borrow data previously stored with [`send_data<T>`][Self::send_data],
+    /// in order to update it in place.
+    /// Unlike a new call to [`send_data<T>`][Self::send_data], this doesn't call
+    /// [`PamData::cleanup`].
+    ///
+    /// Returns the same errors as [`retrieve_data<T>`][Self::retrieve_data].
+    /// # Example
+    /// ```rust
+    /// # use pamsm::{Pam, PamData, PamLibExt, PamModuleResult};
+    /// struct Attempts(u32);
+    /// impl PamData for Attempts {}
+    ///
+    /// # fn wrapper(pamh: &mut Pam) -> PamModuleResult<()> {
+    /// match pamh.retrieve_data_mut::<Attempts>("attempts") {
+    ///     Ok(attempts) => attempts.0 += 1,
+    ///     Err(_) => pamh.send_data("attempts", Attempts(1))?,
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    fn retrieve_data_mut<T: PamData + Send + 'static>(
+        &mut self,
+        module_name: &str,
+    ) -> PamModuleResult<&mut T>;
+
+    /// Remove the data stored under `module_name` with [`send_data`][Self::send_data] or
+    /// [`send_bytes`][Self::send_bytes].
+    /// The cleanup callback of the data is called right away, with the `DATA_REPLACE` flag.
+    ///
+    /// Data stored with `send_serialized` is removed with `remove_serialized`.
+    ///
+    /// Returns PamError::NO_MODULE_DATA if there is no data under `module_name`.
+    fn remove_data(&mut self, module_name: &str) -> PamModuleResult<()>;
+
+    /// Remove the data stored under `module_name` with [`send_data<T>`][Self::send_data],
+    /// and return it. [`PamData::cleanup`] isn't called, since the data is handed back.
+    ///
+    /// Returns the same errors as [`retrieve_data<T>`][Self::retrieve_data].
+    fn take_data<T: PamData + Send + 'static>(&mut self, module_name: &str) -> PamModuleResult<T>;
 
     /// Similar to [`send_data`][Self::send_data], but only works with [`Vec<u8>`][std::vec::Vec].
     /// The PamData trait doesn't have to be implemented on the data, a callback can be passed
-    /// as an argument instead.
+    /// as an argument instead. If pam fails to store the data, the callback isn't called.
+    /// # Example
+    /// ```rust
+    /// # use pamsm::{Pam, PamLibExt, PamModuleResult};
+    /// # fn wrapper(pamh: &mut Pam, path: std::path::PathBuf) -> PamModuleResult<()> {
+    /// pamh.send_bytes(
+    ///     "token",
+    ///     vec![0u8; 32],
+    ///     Some(Box::new(move |token, _, _, _| {
+    ///         let _ = std::fs::write(path, token);
+    ///     })),
+    /// )
+    /// # }
+    /// ```
     fn send_bytes(
-        &self,
+        &mut self,
         module_name: &str,
         data: Vec<u8>,
         cb: Option<PamCleanupCb>,
-    ) -> PamResult<()>;
+    ) -> PamModuleResult<()>;
 
     /// Retrieve bytes previously stored with [`send_bytes`][Self::send_bytes].
     /// The result is a clone of the data.
-    fn retrieve_bytes(&self, module_name: &str) -> PamResult<Vec<u8>>;
+    fn retrieve_bytes(&self, module_name: &str) -> PamModuleResult<Vec<u8>>;
+
+    /// Serialize `data` and store it under the name `module_name`.
+    ///
+    /// Unlike [`send_data`][Self::send_data], the data is stored in a self-describing,
+    /// versioned encoding, which doesn't depend on how the module was compiled.
+    /// It can then be retrieved with [`retrieve_serialized`][Self::retrieve_serialized]
+    /// by any module of the stack using this crate, to share information such as
+    /// the authentication method used.
+    ///
+    /// Returns PamError::BUF_ERR if `data` can't be serialized.
+    #[cfg(feature = "serde")]
+    fn send_serialized<T: serde::Serialize>(
+        &mut self,
+        module_name: &str,
+        data: &T,
+    ) -> PamModuleResult<()>;
+
+    /// Retrieve data previously stored with [`send_serialized`][Self::send_serialized],
+    /// possibly by a different module.
+    ///
+    /// Returns PamError::NO_MODULE_DATA if there is no serialized data under `module_name`,
+    /// and PamError::BUF_ERR if it uses an unknown version of the encoding or can't be
+    /// deserialized into `T`.
+    /// # Example
+    /// ```rust
+    /// # extern crate serde;
+    /// # extern crate pamsm;
+    /// # use pamsm::{Pam, PamLibExt, PamModuleResult};
+    /// #[derive(serde::Serialize, serde::Deserialize)]
+    /// struct AuthMethod {
+    ///     otp: bool,
+    /// }
+    ///
+    /// # fn wrapper(pamh: &mut Pam) -> PamModuleResult<()> {
+    /// // In the OTP module
+    /// pamh.send_serialized("auth_method", &AuthMethod { otp: true })?;
+    /// // In another module
+    /// let method: AuthMethod = pamh.retrieve_serialized("auth_method")?;
+    /// // Once it isn't needed anymore
+    /// pamh.remove_serialized("auth_method")?;
+    /// # Ok(())
+    /// # }
+    /// # fn main() {}
+    /// ```
+    #[cfg(feature = "serde")]
+    fn retrieve_serialized<T: serde::de::DeserializeOwned>(
+        &self,
+        module_name: &str,
+    ) -> PamModuleResult<T>;
+
+    /// Remove the data stored under `module_name` with
+    /// [`send_serialized`][Self::send_serialized], which is zeroed and freed right away.
+    ///
+    /// Returns PamError::NO_MODULE_DATA if there is no data under `module_name`.
+    #[cfg(feature = "serde")]
+    fn remove_serialized(&mut self, module_name: &str) -> PamModuleResult<()>;
 
     /// Send a message to syslog.
-    fn syslog(&self, lvl: LogLvl, msg: &str) -> PamResult<()>;
+    fn syslog(&self, lvl: LogLvl, msg: &str) -> PamModuleResult<()>;
+
+    /// Parse the module arguments into `T`, see [`PamArgs::parse_with`].
+    ///
+    /// Unknown arguments ignored with [`UnknownArgs::Warn`] are logged as warnings.
+    /// Invalid arguments return a SERVICE_ERR caused by the [`PamArgError`](crate::PamArgError).
+    fn parse_args<T: PamArgs, S: PamArgStr>(
+        &self,
+        args: &[S],
+        unknown: UnknownArgs,
+    ) -> PamModuleResult<T>;
 }
 
 impl From<NulError> for PamError {
@@ -209,10 +763,10 @@ impl From<NulError> for PamError {
 }
 
 impl PamLibExt for Pam {
-    fn get_user(&self, prompt: Option<&str>) -> PamResult<Option<&CStr>> {
+    fn get_user(&self, prompt: Option<&str>) -> PamModuleResult<Option<&CStr>> {
         let cprompt = match prompt {
             None => None,
-            Some(p) => Some(CString::new(p)?),
+            Some(p) => Some(CString::new(p).context("invalid user prompt")?),
         };
         let mut raw_user: *const c_char = ptr::null();
         let r = unsafe {
@@ -224,9 +778,9 @@ impl PamLibExt for Pam {
         };
 
         if raw_user.is_null() {
-            r.to_result(None)
+            Ok(r.to_result(None)?)
         } else {
-            r.to_result(Some(unsafe { CStr::from_ptr(raw_user) }))
+            Ok(r.to_result(Some(unsafe { CStr::from_ptr(raw_user) }))?)
         }
     }
 
@@ -234,18 +788,20 @@ impl PamLibExt for Pam {
         self.get_cstr_item(PamItemType::USER)
     }
 
-    fn get_cached_authtok(&self) -> PamResult<Option<&CStr>> {
+    fn get_cached_authtok(&self) -> PamResult<Option<&SecretStr>> {
         self.get_cstr_item(PamItemType::AUTHTOK)
+            .map(|at| at.map(SecretStr::new))
     }
 
-    fn get_cached_oldauthtok(&self) -> PamResult<Option<&CStr>> {
+    fn get_cached_oldauthtok(&self) -> PamResult<Option<&SecretStr>> {
         self.get_cstr_item(PamItemType::OLDAUTHTOK)
+            .map(|at| at.map(SecretStr::new))
     }
 
-    fn get_authtok(&self, prompt: Option<&str>) -> PamResult<Option<&CStr>> {
+    fn get_authtok(&self, prompt: Option<&str>) -> PamModuleResult<Option<&SecretStr>> {
         let cprompt = match prompt {
             None => None,
-            Some(p) => Some(CString::new(p)?),
+            Some(p) => Some(CString::new(p).context("invalid password prompt")?),
         };
         let mut raw_at: *const c_char = ptr::null();
         let r = unsafe {
@@ -258,35 +814,156 @@ impl PamLibExt for Pam {
         };
 
         if raw_at.is_null() {
-            r.to_result(None)
+            Ok(r.to_result(None)?)
         } else {
-            r.to_result(unsafe { Some(CStr::from_ptr(raw_at)) })
+            Ok(r.to_result(unsafe { Some(SecretStr::new(CStr::from_ptr(raw_at))) })?)
         }
     }
 
-    fn set_authtok(&self, authtok: &CString) -> PamResult<()> {
+    fn set_authtok(&mut self, authtok: &SecretStr) -> PamResult<()> {
         unsafe {
             set_item(
                 self.0,
                 PamItemType::AUTHTOK,
-                authtok.as_ptr() as *const c_void,
+                authtok.expose().as_ptr() as *const c_void,
+            )
+        }
+    }
+
+    fn set_oldauthtok(&mut self, oldauthtok: &SecretStr) -> PamResult<()> {
+        unsafe {
+            set_item(
+                self.0,
+                PamItemType::OLDAUTHTOK,
+                oldauthtok.expose().as_ptr() as *const c_void,
             )
         }
     }
 
+    fn set_user<S: PamItemStr + ?Sized>(&mut self, user: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::USER, user)
+    }
+
+    fn get_tty(&self) -> PamResult<Option<&CStr>> {
+        self.get_cstr_item(PamItemType::TTY)
+    }
+
+    fn set_tty<S: PamItemStr + ?Sized>(&mut self, tty: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::TTY, tty)
+    }
+
     fn get_rhost(&self) -> PamResult<Option<&CStr>> {
         self.get_cstr_item(PamItemType::RHOST)
     }
 
+    fn set_rhost<S: PamItemStr + ?Sized>(&mut self, rhost: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::RHOST, rhost)
+    }
+
     fn get_ruser(&self) -> PamResult<Option<&CStr>> {
         self.get_cstr_item(PamItemType::RUSER)
     }
 
+    fn set_ruser<S: PamItemStr + ?Sized>(&mut self, ruser: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::RUSER, ruser)
+    }
+
+    fn get_user_prompt(&self) -> PamResult<Option<&CStr>> {
+        self.get_cstr_item(PamItemType::USER_PROMPT)
+    }
+
+    fn set_user_prompt<S: PamItemStr + ?Sized>(&mut self, prompt: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::USER_PROMPT, prompt)
+    }
+
+    fn get_xdisplay(&self) -> PamResult<Option<&CStr>> {
+        self.get_cstr_item(PamItemType::XDISPLAY)
+    }
+
+    fn set_xdisplay<S: PamItemStr + ?Sized>(&mut self, xdisplay: &S) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::XDISPLAY, xdisplay)
+    }
+
+    fn get_authtok_type(&self) -> PamResult<Option<&CStr>> {
+        self.get_cstr_item(PamItemType::AUTHTOK_TYPE)
+    }
+
+    fn set_authtok_type<S: PamItemStr + ?Sized>(
+        &mut self,
+        authtok_type: &S,
+    ) -> PamModuleResult<()> {
+        self.set_str_item(PamItemType::AUTHTOK_TYPE, authtok_type)
+    }
+
+    fn get_xauthdata(&self) -> PamResult<Option<PamXAuthData<'_>>> {
+        let mut raw_item: *const c_void = ptr::null();
+        let r = unsafe {
+            PamError::new(pam_get_item(
+                self.0,
+                PamItemType::XAUTHDATA as c_int,
+                &mut raw_item,
+            ))
+        };
+        if r != PamError::SUCCESS {
+            return Err(r);
+        }
+
+        let raw = match unsafe { (raw_item as *const PamXAuthDataRaw).as_ref() } {
+            Some(raw) if !raw.name.is_null() || !raw.data.is_null() => raw,
+            _ => return Ok(None),
+        };
+        // pam keeps the item allocated until it is replaced or pam_end (3) is called
+        Ok(Some(PamXAuthData {
+            name: unsafe { raw_bytes(raw.name, raw.namelen) },
+            data: unsafe { raw_bytes(raw.data, raw.datalen) },
+        }))
+    }
+
+    fn set_xauthdata(&mut self, xauthdata: &PamXAuthData) -> PamModuleResult<()> {
+        // pam copies the name with strdup, it has to be null terminated
+        let cname = CString::new(xauthdata.name).context("invalid PAM_XAUTHDATA name")?;
+        if xauthdata.name.len() > c_int::MAX as usize || xauthdata.data.len() > c_int::MAX as usize
+        {
+            return Err(PamError::BUF_ERR.into());
+        }
+
+        let raw = PamXAuthDataRaw {
+            namelen: xauthdata.name.len() as c_int,
+            name: cname.as_ptr(),
+            datalen: xauthdata.data.len() as c_int,
+            data: xauthdata.data.as_ptr() as *const c_char,
+        };
+        unsafe {
+            set_item(
+                self.0,
+                PamItemType::XAUTHDATA,
+                &raw as *const PamXAuthDataRaw as *const c_void,
+            )?
+        };
+        Ok(())
+    }
+
     fn get_service(&self) -> PamResult<Option<&CStr>> {
         self.get_cstr_item(PamItemType::SERVICE)
     }
 
-    fn conv(&self, prompt: Option<&str>, style: PamMsgStyle) -> PamResult<Option<&CStr>> {
+    fn conv(
+        &self,
+        prompt: Option<&str>,
+        style: PamMsgStyle,
+    ) -> PamModuleResult<Option<PamConvResponse>> {
+        self.conv_multi(&[(style, prompt.unwrap_or(""))])
+            .map(|mut resps| resps.pop().and_then(|r| r))
+    }
+
+    fn conv_multi(
+        &self,
+        msgs: &[(PamMsgStyle, &str)],
+    ) -> PamModuleResult<Vec<Option<PamConvResponse>>> {
+        if msgs.len() > PAM_MAX_NUM_MSG {
+            return Err(PamError::CONV_ERR.into());
+        }
+
         let mut conv_pointer: *const c_void = ptr::null();
         let r = unsafe {
             PamError::new(pam_get_item(
@@ -297,39 +974,83 @@ impl PamLibExt for Pam {
         };
 
         if r != PamError::SUCCESS {
-            return Err(r);
+            return Err(r.into());
         }
 
-        if conv_pointer.is_null() {
-            return Ok(None);
+        if conv_pointer.is_null() || msgs.is_empty() {
+            return Ok(msgs.iter().map(|_| None).collect());
         }
 
         let conv = unsafe { &*(conv_pointer as *const PamConv) };
+        let msg_cstrs = msgs
+            .iter()
+            .map(|&(_, text)| CString::new(text))
+            .collect::<Result<Vec<CString>, NulError>>()
+            .context("invalid conversation message")?;
+        let pam_msgs: Vec<PamMessage> = msgs
+            .iter()
+            .zip(msg_cstrs.iter())
+            .map(|(&(style, _), text)| PamMessage {
+                msg_style: style,
+                msg: text.as_ptr(),
+            })
+            .collect();
+        // Linux-PAM reads the messages as an array of pointers while other implementations
+        // read them as a pointer to an array, this layout works with both.
+        let mut pam_msg_ptrs: Vec<*const PamMessage> =
+            pam_msgs.iter().map(|m| m as *const PamMessage).collect();
         let mut resp_ptr: *mut PamResponse = ptr::null_mut();
-        let msg_cstr = CString::new(prompt.unwrap_or(""))?;
-        let msg = PamMessage {
-            msg_style: style,
-            msg: msg_cstr.as_ptr(),
-        };
 
         match conv.cb.map(|cb| {
             PamError::new(cb(
-                1,
-                &mut (&msg as *const PamMessage),
+                msgs.len() as c_int,
+                pam_msg_ptrs.as_mut_ptr(),
                 &mut resp_ptr,
                 conv.appdata_ptr,
             ))
         }) {
+            Some(PamError::SUCCESS) if resp_ptr.is_null() => {
+                Ok(msgs.iter().map(|_| None).collect())
+            }
             Some(PamError::SUCCESS) => {
-                Ok(unsafe { (*resp_ptr).resp }.map(|r| unsafe { CStr::from_ptr(r.as_ptr()) }))
+                // Take ownership of each response buffer, then release the array itself.
+                let resps = (0..msgs.len())
+                    .map(|i| unsafe { (*resp_ptr.add(i)).resp }.map(PamConvResponse))
+                    .collect();
+                unsafe { free(resp_ptr as *mut c_void) };
+                Ok(resps)
             }
-            Some(ret) => Err(ret),
-            None => Ok(None),
+            Some(ret) => Err(ret.into()),
+            None => Ok(msgs.iter().map(|_| None).collect()),
+        }
+    }
+
+    fn fail_delay(&self, delay: Duration) -> PamResult<()> {
+        let usec = delay.as_micros().min(c_uint::MAX as u128) as c_uint;
+        unsafe { PamError::new(pam_fail_delay(self.0, usec)).to_result(()) }
+    }
+
+    fn get_fail_delay_fn(&self) -> PamResult<Option<PamFailDelayFn>> {
+        let mut raw_item: *const c_void = ptr::null();
+        let r = unsafe {
+            PamError::new(pam_get_item(
+                self.0,
+                PamItemType::FAIL_DELAY as c_int,
+                &mut raw_item,
+            ))
+        };
+        if raw_item.is_null() {
+            r.to_result(None)
+        } else {
+            // The item is a function pointer stored as a void pointer
+            r.to_result(Some(unsafe {
+                std::mem::transmute::<*const c_void, PamFailDelayFn>(raw_item)
+            }))
         }
     }
 
-    fn getenv(&self, name: &str) -> PamResult<Option<&CStr>> {
-        let cname = CString::new(name)?;
+    fn getenv(&self, name: &str) -> PamModuleResult<Option<&CStr>> {
+        let cname = CString::new(name).context("invalid environment variable name")?;
         let cenv = unsafe { pam_getenv(self.0, cname.as_ptr()) };
 
         if cenv.is_null() {
@@ -339,75 +1060,273 @@ impl PamLibExt for Pam {
         }
     }
 
-    fn putenv(&self, name_value: &str) -> PamResult<()> {
-        let cenv = CString::new(name_value)?;
-        unsafe { PamError::new(pam_putenv(self.0, cenv.as_ptr())).to_result(()) }
+    fn putenv(&mut self, name_value: &str) -> PamModuleResult<()> {
+        let cenv = CString::new(name_value).context("invalid environment variable")?;
+        unsafe { PamError::new(pam_putenv(self.0, cenv.as_ptr())).to_result(())? };
+        Ok(())
     }
 
-    unsafe fn send_data<T: PamData + Clone + Send>(
-        &self,
+    fn getenvlist(&self) -> PamResult<PamEnvList> {
+        match NonNull::new(unsafe { pam_getenvlist(self.0) }) {
+            Some(list) => Ok(PamEnvList { list, pos: 0 }),
+            None => Err(PamError::BUF_ERR),
+        }
+    }
+
+    fn putenv_many<I, K, V>(&mut self, vars: I) -> PamModuleResult<()>
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: AsRef<str>,
+        V: AsRef<str>,
+    {
+        for (name, value) in vars {
+            check_env_name(name.as_ref())?;
+            self.putenv(&format!("{}={}", name.as_ref(), value.as_ref()))?;
+        }
+        Ok(())
+    }
+
+    fn unsetenv_many<I, K>(&mut self, names: I) -> PamModuleResult<()>
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<str>,
+    {
+        for name in names {
+            check_env_name(name.as_ref())?;
+            match self.putenv(name.as_ref()) {
+                // Returned when the variable isn't set
+                Err(ref e) if e.code() == PamError::BAD_ITEM => (),
+                r => r?,
+            }
+        }
+        Ok(())
+    }
+
+    fn send_data<T: PamData + Send + 'static>(
+        &mut self,
         module_name: &str,
         data: T,
-    ) -> PamResult<()> {
-        // The data has to be allocated on the heap because it will outlive the call stack.
-        let data_copy = Box::new(data);
-        PamError::new(pam_set_data(
-            self.0,
-            CString::new(module_name)?.as_ptr(),
-            Box::into_raw(data_copy) as *mut c_void,
-            Some(pam_data_cleanup::<T>),
-        ))
-        .to_result(())
+    ) -> PamModuleResult<()> {
+        self.set_typed_data(module_name, data, pam_data_cleanup::<TypedData<T>>)
     }
 
-    unsafe fn retrieve_data<T: PamData + Clone + Send>(&self, module_name: &str) -> PamResult<T> {
-        let mut data_ptr: *const c_void = ptr::null();
-        // pam_get_data should be safe as long as T is the type that what used in send_data.
-        PamError::new(pam_get_data(
-            self.0,
-            CString::new(module_name)?.as_ptr(),
-            &mut data_ptr,
-        ))
-        .to_result(data_ptr as *const T)
-        .map(|ptr| (*ptr).clone()) // pam guaranties the data is valid when SUCCESS is returned.
+    fn retrieve_data<T: PamData + Clone + Send + 'static>(
+        &self,
+        module_name: &str,
+    ) -> PamModuleResult<T> {
+        self.retrieve_data_ref::<T>(module_name).cloned()
     }
 
-    fn send_bytes(
+    fn retrieve_data_ref<T: PamData + Send + 'static>(
         &self,
         module_name: &str,
+    ) -> PamModuleResult<&T> {
+        // pam guaranties the data is valid when SUCCESS is returned.
+        // It then stays valid until it is replaced, which requires a mutable borrow.
+        let ptr = self.get_typed_data::<T>(module_name)?;
+        unsafe { (*ptr).data.as_ref() }.ok_or_else(|| PamError::NO_MODULE_DATA.into())
+    }
+
+    fn retrieve_data_mut<T: PamData + Send + 'static>(
+        &mut self,
+        module_name: &str,
+    ) -> PamModuleResult<&mut T> {
+        let ptr = self.get_typed_data::<T>(module_name)?;
+        unsafe { (*ptr).data.as_mut() }.ok_or_else(|| PamError::NO_MODULE_DATA.into())
+    }
+
+    fn remove_data(&mut self, module_name: &str) -> PamModuleResult<()> {
+        self.remove_named_data(TYPED_DATA_PREFIX, module_name)
+    }
+
+    fn take_data<T: PamData + Send + 'static>(&mut self, module_name: &str) -> PamModuleResult<T> {
+        let ptr = self.get_typed_data::<T>(module_name)?;
+        let data = unsafe { (*ptr).data.take() }.ok_or(PamError::NO_MODULE_DATA)?;
+        // Only the empty envelope is left to clean up
+        self.remove_data(module_name)?;
+        Ok(data)
+    }
+
+    fn send_bytes(
+        &mut self,
+        module_name: &str,
         data: Vec<u8>,
         cb: Option<PamCleanupCb>,
-    ) -> PamResult<()> {
+    ) -> PamModuleResult<()> {
         let data_cb = PamByteData { cb, data };
-        unsafe { self.send_data(module_name, data_cb) }
+        self.set_typed_data(module_name, data_cb, pam_bytes_cleanup)
+    }
+
+    fn retrieve_bytes(&self, module_name: &str) -> PamModuleResult<Vec<u8>> {
+        let ptr = self.get_typed_data::<PamByteData>(module_name)?;
+        unsafe { (*ptr).data.as_ref() }
+            .map(|data_cb| data_cb.data.clone())
+            .ok_or_else(|| PamError::NO_MODULE_DATA.into())
+    }
+
+    #[cfg(feature = "serde")]
+    fn send_serialized<T: serde::Serialize>(
+        &mut self,
+        module_name: &str,
+        data: &T,
+    ) -> PamModuleResult<()> {
+        let cname = data_name(SERIALIZED_DATA_PREFIX, module_name)?;
+        let mut payload = serde_json::to_vec(data)
+            .map_err(|e| PamModuleError::with_source(PamError::BUF_ERR, e))?;
+        let header = SerializedDataHeader {
+            magic: SERIALIZED_DATA_MAGIC,
+            version: SERIALIZED_DATA_VERSION,
+            reserved: 0,
+            len: payload.len() as u64,
+        };
+
+        let header_len = std::mem::size_of::<SerializedDataHeader>();
+        let buf = unsafe { malloc(header_len + payload.len()) as *mut u8 };
+        if buf.is_null() {
+            zeroize(&mut payload);
+            return Err(PamError::BUF_ERR.into());
+        }
+        unsafe {
+            ptr::write(buf as *mut SerializedDataHeader, header);
+            ptr::copy_nonoverlapping(payload.as_ptr(), buf.add(header_len), payload.len());
+        }
+        zeroize(&mut payload);
+
+        let r = unsafe {
+            PamError::new(pam_set_data(
+                self.0,
+                cname.as_ptr(),
+                buf as *mut c_void,
+                Some(pam_serialized_cleanup),
+            ))
+        };
+        if r != PamError::SUCCESS {
+            unsafe {
+                zeroize(serialized_payload(buf as *mut SerializedDataHeader));
+                free(buf as *mut c_void);
+            }
+            return Err(r.into());
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "serde")]
+    fn retrieve_serialized<T: serde::de::DeserializeOwned>(
+        &self,
+        module_name: &str,
+    ) -> PamModuleResult<T> {
+        let cname = data_name(SERIALIZED_DATA_PREFIX, module_name)?;
+        let mut data_ptr: *const c_void = ptr::null();
+        let r = unsafe { PamError::new(pam_get_data(self.0, cname.as_ptr(), &mut data_ptr)) };
+        if r != PamError::SUCCESS {
+            return Err(r.into());
+        }
+
+        let header = match unsafe { (data_ptr as *const SerializedDataHeader).as_ref() } {
+            Some(header) if header.magic == SERIALIZED_DATA_MAGIC => header,
+            _ => return Err(PamError::NO_MODULE_DATA.into()),
+        };
+        if header.version != SERIALIZED_DATA_VERSION {
+            return Err(PamModuleError::new(PamError::BUF_ERR).context(format!(
+                "unknown version {} of serialized module data",
+                header.version
+            )));
+        }
+        let payload = unsafe { serialized_payload(data_ptr as *mut SerializedDataHeader) };
+        serde_json::from_slice(payload)
+            .map_err(|e| PamModuleError::with_source(PamError::BUF_ERR, e))
     }
 
-    fn retrieve_bytes(&self, module_name: &str) -> PamResult<Vec<u8>> {
-        unsafe { self.retrieve_data::<PamByteData>(module_name) }.map(|data_cb| data_cb.data)
+    #[cfg(feature = "serde")]
+    fn remove_serialized(&mut self, module_name: &str) -> PamModuleResult<()> {
+        self.remove_named_data(SERIALIZED_DATA_PREFIX, module_name)
     }
 
-    fn syslog(&self, lvl: LogLvl, msg: &str) -> PamResult<()> {
+    fn syslog(&self, lvl: LogLvl, msg: &str) -> PamModuleResult<()> {
         let fmt = b"%s\0".as_ptr() as *const c_char;
-        let cmsg = CString::new(msg)?;
+        let cmsg = CString::new(msg).context("invalid syslog message")?;
         unsafe {
             pam_syslog(self.0, lvl as c_int, fmt, cmsg.as_ptr());
         }
         Ok(())
     }
+
+    fn parse_args<T: PamArgs, S: PamArgStr>(
+        &self,
+        args: &[S],
+        unknown: UnknownArgs,
+    ) -> PamModuleResult<T> {
+        T::parse_with(args, unknown, |warning| {
+            let _ = self.syslog(LogLvl::WARNING, &format!("ignoring {}", warning));
+        })
+        .context("invalid module arguments")
+    }
+}
+
+// Name under which the data of `module_name` is stored, in the namespace given by `prefix`
+fn data_name(prefix: &str, module_name: &str) -> PamModuleResult<CString> {
+    // Checked alone, so that the position in the error is the one in `module_name`
+    let name = CString::new(module_name).context("invalid module data name")?;
+    let mut bytes = prefix.as_bytes().to_vec();
+    bytes.extend_from_slice(name.as_bytes());
+    // Neither the prefix nor the name contain any null byte
+    Ok(unsafe { CString::from_vec_unchecked(bytes) })
+}
+
+type PamDataCleanupCb = unsafe extern "C" fn(PamHandle, *mut c_void, c_int);
+
+// The error status of the cleanup callbacks is combined with PAM_DATA_REPLACE and PAM_DATA_SILENT
+fn cleanup_flags(error_status: c_int) -> PamFlags {
+    PamFlags::from_bits_truncate(error_status) & (PamFlags::DATA_REPLACE | PamFlags::DATA_SILENT)
 }
 
-unsafe extern "C" fn pam_data_cleanup<T: PamData + Clone + Send>(
+unsafe extern "C" fn pam_data_cleanup<T: PamData>(
     handle: PamHandle,
     data: *mut c_void,
     error_status: c_int,
 ) {
     Box::from_raw(data as *mut T).cleanup(
-        Pam(handle),
-        PamFlags::from_bits_truncate(error_status),
+        &Pam(handle),
+        cleanup_flags(error_status),
         PamError::new(error_status & 0xff),
     );
 }
 
+// The callback of PamByteData takes ownership of the bytes, it can't go through PamData.
+unsafe extern "C" fn pam_bytes_cleanup(handle: PamHandle, data: *mut c_void, error_status: c_int) {
+    let byte_data = Box::from_raw(data as *mut TypedData<PamByteData>).data;
+    if let Some(PamByteData { cb: Some(cb), data }) = byte_data {
+        cb(
+            data,
+            &Pam(handle),
+            cleanup_flags(error_status),
+            PamError::new(error_status & 0xff),
+        );
+    }
+}
+
+#[cfg(feature = "serde")]
+unsafe fn serialized_payload<'a>(header: *mut SerializedDataHeader) -> &'a mut [u8] {
+    std::slice::from_raw_parts_mut(
+        (header as *mut u8).add(std::mem::size_of::<SerializedDataHeader>()),
+        (*header).len as usize,
+    )
+}
+
+#[cfg(feature = "serde")]
+unsafe extern "C" fn pam_serialized_cleanup(_: PamHandle, data: *mut c_void, _: c_int) {
+    zeroize(serialized_payload(data as *mut SerializedDataHeader));
+    free(data);
+}
+
+unsafe fn raw_bytes<'a>(ptr: *const c_char, len: c_int) -> &'a [u8] {
+    if ptr.is_null() || len <= 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr as *const u8, len as usize)
+    }
+}
+
 unsafe fn set_item(pamh: PamHandle, item_type: PamItemType, item: *const c_void) -> PamResult<()> {
     PamError::new(pam_set_item(pamh, item_type as c_int, item)).to_result(())
 }
@@ -421,12 +1340,13 @@ extern "C" {
     pub fn pam_putenv(pamh: PamHandle, name_value: *const c_char) -> c_int;
     pub fn pam_getenv(pamh: PamHandle, name: *const c_char) -> *const c_char;
     pub fn pam_getenvlist(pamh: PamHandle) -> *mut *mut c_char;
+    pub fn pam_fail_delay(pamh: PamHandle, musec_delay: c_uint) -> c_int;
 
     pub fn pam_set_data(
         pamh: PamHandle,
         module_data_name: *const c_char,
         data: *mut c_void,
-        cleanup: Option<unsafe extern "C" fn(_: PamHandle, _: *mut c_void, _: c_int)>,
+        cleanup: Option<PamDataCleanupCb>,
     ) -> c_int;
     pub fn pam_get_data(
         pamh: PamHandle,
@@ -443,3 +1363,10 @@ extern "C" {
 
     pub fn pam_syslog(pamh: PamHandle, priority: c_int, fmt: *const c_char, ...) -> c_void;
 }
+
+// Conversation responses and environment lists are allocated with malloc.
+extern "C" {
+    #[cfg(feature = "serde")]
+    fn malloc(size: usize) -> *mut c_void;
+    fn free(ptr: *mut c_void);
+}
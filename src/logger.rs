@@ -0,0 +1,79 @@
+//! Backend of the `log` crate sending the records to syslog.
+
+use entry::{debug_call, syslog_current};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pam_types::LogLvl;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+
+/// [`log`] backend sending the records to syslog.
+///
+/// It is installed by `pam_module!` on the first call to the module, unless another logger
+/// was set before, for instance by the `init` function of `pam_module!`. The records are sent with `pam_syslog` during a call to the module, so
+/// they are prefixed with the module and service names, and with syslog(3) otherwise.
+///
+/// Records up to the level set with [`PamLogger::set_level`] are logged, `Info` by default.
+/// When the module is given the `debug` argument, `Debug` records are logged during its calls.
+pub struct PamLogger {
+    level: AtomicUsize,
+}
+
+static LOGGER: PamLogger = PamLogger {
+    level: AtomicUsize::new(LevelFilter::Info as usize),
+};
+
+impl PamLogger {
+    /// Set the maximum level of the records to log.
+    pub fn set_level(level: LevelFilter) {
+        LOGGER.level.store(level as usize, Ordering::Relaxed);
+        log::set_max_level(level.max(LevelFilter::Debug));
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        let level = match self.level.load(Ordering::Relaxed) {
+            0 => LevelFilter::Off,
+            1 => LevelFilter::Error,
+            2 => LevelFilter::Warn,
+            3 => LevelFilter::Info,
+            4 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        };
+        if debug_call() {
+            level.max(LevelFilter::Debug)
+        } else {
+            level
+        }
+    }
+}
+
+impl Log for PamLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let lvl = match record.level() {
+            Level::Error => LogLvl::ERR,
+            Level::Warn => LogLvl::WARNING,
+            Level::Info => LogLvl::INFO,
+            Level::Debug | Level::Trace => LogLvl::DEBUG,
+        };
+        syslog_current(lvl, &record.args().to_string());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, if no other logger is set.
+pub(crate) fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        if log::set_logger(&LOGGER).is_ok() {
+            log::set_max_level(LOGGER.max_level().max(LevelFilter::Debug));
+        }
+    });
+}
@@ -3,7 +3,10 @@
 #![allow(non_camel_case_types)]
 #![allow(clippy::upper_case_acronyms)]
 
+use error::PamModuleResult;
+use flags::{AcctFlags, AuthFlags, ChauthtokFlags, CredFlags, SessionFlags};
 use pam_types::PamHandle;
+use std::ffi::CStr;
 use std::fmt;
 use std::os::raw::c_int;
 
@@ -64,6 +67,12 @@ impl std::ops::Deref for PamSendRef<'_> {
     }
 }
 
+impl std::ops::DerefMut for PamSendRef<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0
+    }
+}
+
 impl<'a> From<PamSendRef<'a>> for &'a mut Pam {
     fn from(value: PamSendRef<'a>) -> Self {
         value.0
@@ -79,6 +88,7 @@ impl<'a> From<PamSendRef<'a>> for &'a Pam {
 bitflags! {
     pub struct PamFlags : c_int {
         const DATA_REPLACE = 0x2000_0000;
+        const DATA_SILENT = 0x4000_0000;
         const SILENT = 0x8000;
         const DISALLOW_NULL_AUTHTOK = 0x0001;
         const ESTABLISH_CRED = 0x0002;
@@ -86,6 +96,8 @@ bitflags! {
         const REINITIALIZE_CRED = 0x0008;
         const REFRESH_CRED = 0x0010;
         const CHANGE_EXPIRED_AUTHTOK = 0x0020;
+        const PRELIM_CHECK = 0x4000;
+        const UPDATE_AUTHTOK = 0x2000;
     }
 }
 
@@ -95,6 +107,8 @@ impl fmt::Display for PamError {
     }
 }
 
+impl std::error::Error for PamError {}
+
 macro_rules! int_enum {
     ( $name:ident ($ukey:ident = $uvalue:expr) {
         $( $key:ident = $value:expr ),*
@@ -153,43 +167,152 @@ int_enum! {
     }
 }
 
+pub type PamResult<T> = Result<T, PamError>;
+
 /// Default service module implementation.
 /// All default functions return SERVICE_ERR.
 /// You can override functions depending on what kind of module you implement.
 /// See the respective pam_sm_* man pages for documentation.
+///
+/// The module arguments are passed as given by PAM, they can be parsed with [`PamArgs`].
+///
+/// Each method receives the flags defined by PAM for it, which are checked beforehand:
+/// `setcred` always gets exactly one credential action, and `chauthtok` exactly one phase.
+/// Otherwise, or if other flags are set, the call fails with SERVICE_ERR, or SYSTEM_ERR for
+/// `chauthtok` like libpam.
+///
+/// The handle is only borrowed for the duration of the call, as are the items read from it.
+///
+/// A single instance of the module is created by [`pam_module!`](crate::pam_module) on the
+/// first call, and is shared by all the calls until the module is unloaded. Since applications
+/// may call the module from several threads, it has to be `Send` and `Sync`.
+///
+/// [`PamArgs`]: crate::PamArgs
 pub trait PamServiceModule {
-    fn open_session(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn open_session(&self, _: &mut Pam, _: SessionFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 
-    fn close_session(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn close_session(&self, _: &mut Pam, _: SessionFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 
-    fn authenticate(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn authenticate(&self, _: &mut Pam, _: AuthFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 
-    fn setcred(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn setcred(&self, _: &mut Pam, _: CredFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 
-    fn acct_mgmt(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn acct_mgmt(&self, _: &mut Pam, _: AcctFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 
-    fn chauthtok(_: Pam, _: PamFlags, _: Vec<String>) -> PamError {
+    fn chauthtok(&self, _: &mut Pam, _: ChauthtokFlags, _: &[&CStr]) -> PamError {
         PamError::SERVICE_ERR
     }
 }
 
+/// Service module implementation returning a [`PamModuleResult`], so errors can be propagated
+/// with `?`. `Ok(())` is returned to PAM as SUCCESS.
+///
+/// The code of an error is returned to PAM, after logging its context and source to syslog
+/// if it has any, see [`PamModuleError`](crate::PamModuleError). An error with the SUCCESS
+/// code is returned as SERVICE_ERR.
+///
+/// Types implementing this trait implement [`PamServiceModule`] and can be passed to
+/// [`pam_module!`](crate::pam_module).
+/// All default functions return SERVICE_ERR.
+pub trait PamResultServiceModule {
+    fn open_session(&self, _: &mut Pam, _: SessionFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+
+    fn close_session(&self, _: &mut Pam, _: SessionFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+
+    fn authenticate(&self, _: &mut Pam, _: AuthFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+
+    fn setcred(&self, _: &mut Pam, _: CredFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+
+    fn acct_mgmt(&self, _: &mut Pam, _: AcctFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+
+    fn chauthtok(&self, _: &mut Pam, _: ChauthtokFlags, _: &[&CStr]) -> PamModuleResult<()> {
+        Err(PamError::SERVICE_ERR.into())
+    }
+}
+
+impl<T: PamResultServiceModule> PamServiceModule for T {
+    fn open_session(&self, pamh: &mut Pam, flags: SessionFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::open_session(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+
+    fn close_session(&self, pamh: &mut Pam, flags: SessionFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::close_session(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+
+    fn authenticate(&self, pamh: &mut Pam, flags: AuthFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::authenticate(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+
+    fn setcred(&self, pamh: &mut Pam, flags: CredFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::setcred(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+
+    fn acct_mgmt(&self, pamh: &mut Pam, flags: AcctFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::acct_mgmt(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+
+    fn chauthtok(&self, pamh: &mut Pam, flags: ChauthtokFlags, args: &[&CStr]) -> PamError {
+        let res = PamResultServiceModule::chauthtok(self, pamh, flags, args);
+        result_code(pamh, res)
+    }
+}
+
+// Code returned to PAM for the result of a `PamResultServiceModule` method
+fn result_code(pamh: &Pam, res: PamModuleResult<()>) -> PamError {
+    match res {
+        Ok(()) => PamError::SUCCESS,
+        Err(err) => err.report(pamh),
+    }
+}
+
 /// Define entrypoints for the PAM module.
 ///
 /// This macro must be called exactly once in a PAM module.
-/// It then exports all the pam_sm_* symbols.
+/// It then exports the pam_sm_* symbols.
 ///
 /// The argument to the macro is a type implementing the
-/// `PamServiceModule` trait.
+/// `PamServiceModule` or `PamResultServiceModule` trait, followed by options:
+/// - `init = <fn>`: function creating the module instance on the first call,
+///   `Default::default()` by default. It runs before `PamLogger` is installed with the `log`
+///   feature, so it can set another logger.
+/// - `on_panic = <code>`: value returned when a method panics, SERVICE_ERR by default.
+///   The panic doesn't unwind into the application, it is logged to syslog.
+/// - `export = [<groups>]`: management groups to export, among `auth` (pam_sm_authenticate
+///   and pam_sm_setcred), `account` (pam_sm_acct_mgmt), `session` (pam_sm_open_session and
+///   pam_sm_close_session) and `password` (pam_sm_chauthtok). All of them by default.
+/// - `others = <code>`: value returned by the functions of the groups which aren't exported.
+///   Without it, their symbols are left undefined, and PAM fails to use the module for them.
+///
+/// When the module is given the `debug` argument, or the `PAMSM_DEBUG` environment variable is
+/// set, each call is logged to syslog with its flags and arguments, then its result and duration.
+/// The argument is parsed like a `bool` field of [`pam_args!`](crate::pam_args), so `debug=yes`
+/// or `[debug=1]` set it too, and `debug=off` unsets it.
+/// The environment variable is ignored in setuid and setgid programs.
 ///
 /// # Example
 ///
@@ -198,54 +321,171 @@ pub trait PamServiceModule {
 /// #[macro_use] extern crate pamsm;
 ///
 /// pam_module!(MyPamService);
+/// // or
+/// pam_module!(
+///     MyPamService,
+///     init = MyPamService::new,
+///     on_panic = PamError::AUTH_ERR,
+///     export = [account],
+///     others = PamError::IGNORE,
+/// );
 /// ```
 #[macro_export]
 macro_rules! pam_module {
-    ($pamsm_ty:ty) => {
+    (@opts [$pamsm_ty:ty] [$init:expr] [$on_panic:expr] [$($export:ident)*] [$($others:expr)?]
+        , init = $new_init:expr $(, $($rest:tt)*)?) => {
+        $crate::pam_module!(
+            @opts [$pamsm_ty] [$new_init] [$on_panic] [$($export)*] [$($others)?] $(, $($rest)*)?
+        );
+    };
+    (@opts [$pamsm_ty:ty] [$init:expr] [$on_panic:expr] [$($export:ident)*] [$($others:expr)?]
+        , on_panic = $new_on_panic:expr $(, $($rest:tt)*)?) => {
+        $crate::pam_module!(
+            @opts [$pamsm_ty] [$init] [$new_on_panic] [$($export)*] [$($others)?] $(, $($rest)*)?
+        );
+    };
+    (@opts [$pamsm_ty:ty] [$init:expr] [$on_panic:expr] [$($export:ident)*] [$($others:expr)?]
+        , export = [$($new_export:ident),* $(,)?] $(, $($rest:tt)*)?) => {
+        $crate::pam_module!(
+            @opts [$pamsm_ty] [$init] [$on_panic] [$($new_export)*] [$($others)?] $(, $($rest)*)?
+        );
+    };
+    (@opts [$pamsm_ty:ty] [$init:expr] [$on_panic:expr] [$($export:ident)*] [$($others:expr)?]
+        , others = $new_others:expr $(, $($rest:tt)*)?) => {
+        $crate::pam_module!(
+            @opts [$pamsm_ty] [$init] [$on_panic] [$($export)*] [$new_others] $(, $($rest)*)?
+        );
+    };
+    (@opts [$pamsm_ty:ty] [$init:expr] [$on_panic:expr] [$($export:ident)*] [$($others:expr)?]
+        $(,)?) => {
+        $crate::pam_module!(@check_groups $($export)*);
+
         // Check trait bound on input type.
-        fn _check_pamsm_trait<T: pamsm::PamServiceModule>() {}
+        fn _check_pamsm_trait<T: $crate::PamServiceModule>() {}
         fn _t() {
             _check_pamsm_trait::<$pamsm_ty>()
         }
 
-        // Callback entry definition.
-        macro_rules! pam_callback {
-            ($pam_cb:ident, $rust_cb:ident) => {
-                #[no_mangle]
-                #[doc(hidden)]
-                pub unsafe extern "C" fn $pam_cb(
-                    pamh: pamsm::Pam,
-                    flags: std::os::raw::c_int,
-                    argc: std::os::raw::c_int,
-                    argv: *const *const std::os::raw::c_char,
-                ) -> std::os::raw::c_int {
-                    use std::os::raw::c_int;
-                    if argc < 0 {
-                        return pamsm::PamError::SERVICE_ERR as std::os::raw::c_int;
-                    }
-
-                    let mut args = Vec::<String>::with_capacity(argc as usize);
-                    for count in 0..(argc as isize) {
-                        match {
-                            std::ffi::CStr::from_ptr(
-                                *argv.offset(count) as *const std::os::raw::c_char
-                            )
-                            .to_str()
-                        } {
-                            Ok(s) => args.push(s.to_owned()),
-                            Err(_) => return pamsm::PamError::SERVICE_ERR as c_int,
-                        };
-                    }
-                    <$pamsm_ty>::$rust_cb(pamh, pamsm::PamFlags::from_bits_unchecked(flags), args) as c_int
-                }
-            };
+        // Module instance, shared by all the callbacks.
+        #[allow(dead_code)]
+        static __PAMSM_INSTANCE: std::sync::OnceLock<$pamsm_ty> = std::sync::OnceLock::new();
+
+        #[allow(dead_code)]
+        fn __pamsm_instance() -> &'static $pamsm_ty {
+            __PAMSM_INSTANCE.get_or_init($init)
         }
 
-        pam_callback!(pam_sm_open_session, open_session);
-        pam_callback!(pam_sm_close_session, close_session);
-        pam_callback!(pam_sm_authenticate, authenticate);
-        pam_callback!(pam_sm_setcred, setcred);
-        pam_callback!(pam_sm_acct_mgmt, acct_mgmt);
-        pam_callback!(pam_sm_chauthtok, chauthtok);
+        #[allow(dead_code)]
+        const __PAMSM_ON_PANIC: $crate::PamError = $on_panic;
+
+        $crate::pam_module!(@group auth [$($export)*] [$($others)?]);
+        $crate::pam_module!(@group account [$($export)*] [$($others)?]);
+        $crate::pam_module!(@group session [$($export)*] [$($others)?]);
+        $crate::pam_module!(@group password [$($export)*] [$($others)?]);
+    };
+
+    // Reject unknown management groups.
+    (@check_groups) => {};
+    (@check_groups auth $($rest:ident)*) => {
+        $crate::pam_module!(@check_groups $($rest)*);
+    };
+    (@check_groups account $($rest:ident)*) => {
+        $crate::pam_module!(@check_groups $($rest)*);
+    };
+    (@check_groups session $($rest:ident)*) => {
+        $crate::pam_module!(@check_groups $($rest)*);
+    };
+    (@check_groups password $($rest:ident)*) => {
+        $crate::pam_module!(@check_groups $($rest)*);
+    };
+    (@check_groups $group:ident $($rest:ident)*) => {
+        compile_error!(concat!(
+            "unknown management group `",
+            stringify!($group),
+            "`, expected auth, account, session or password"
+        ));
+    };
+
+    // Export the callbacks of a management group if it is in the export list,
+    // otherwise stubs returning `others`, if any.
+    (@group auth [auth $($rest:ident)*] $others:tt) => {
+        $crate::pam_module!(@callback pam_sm_authenticate, authenticate);
+        $crate::pam_module!(@callback pam_sm_setcred, setcred);
+    };
+    (@group account [account $($rest:ident)*] $others:tt) => {
+        $crate::pam_module!(@callback pam_sm_acct_mgmt, acct_mgmt);
+    };
+    (@group session [session $($rest:ident)*] $others:tt) => {
+        $crate::pam_module!(@callback pam_sm_open_session, open_session);
+        $crate::pam_module!(@callback pam_sm_close_session, close_session);
+    };
+    (@group password [password $($rest:ident)*] $others:tt) => {
+        $crate::pam_module!(@callback pam_sm_chauthtok, chauthtok);
+    };
+    (@group auth [] [$($others:expr)?]) => {
+        $(
+            $crate::pam_module!(@stub pam_sm_authenticate, $others);
+            $crate::pam_module!(@stub pam_sm_setcred, $others);
+        )?
+    };
+    (@group account [] [$($others:expr)?]) => {
+        $( $crate::pam_module!(@stub pam_sm_acct_mgmt, $others); )?
+    };
+    (@group session [] [$($others:expr)?]) => {
+        $(
+            $crate::pam_module!(@stub pam_sm_open_session, $others);
+            $crate::pam_module!(@stub pam_sm_close_session, $others);
+        )?
+    };
+    (@group password [] [$($others:expr)?]) => {
+        $( $crate::pam_module!(@stub pam_sm_chauthtok, $others); )?
+    };
+    (@group $group:ident [$head:ident $($rest:ident)*] $others:tt) => {
+        $crate::pam_module!(@group $group [$($rest)*] $others);
+    };
+
+    // Callback entry definition.
+    (@callback $pam_cb:ident, $rust_cb:ident) => {
+        #[no_mangle]
+        #[doc(hidden)]
+        pub unsafe extern "C" fn $pam_cb(
+            pamh: $crate::Pam,
+            flags: std::os::raw::c_int,
+            argc: std::os::raw::c_int,
+            argv: *const *const std::os::raw::c_char,
+        ) -> std::os::raw::c_int {
+            $crate::__dispatch(
+                stringify!($pam_cb),
+                pamh,
+                flags,
+                argc,
+                argv,
+                __PAMSM_ON_PANIC,
+                __pamsm_instance,
+                |module, pamh, flags, args| {
+                    $crate::PamServiceModule::$rust_cb(module, pamh, flags, args)
+                },
+            )
+        }
+    };
+    (@stub $pam_cb:ident, $code:expr) => {
+        #[no_mangle]
+        #[doc(hidden)]
+        pub extern "C" fn $pam_cb(
+            _: $crate::Pam,
+            _: std::os::raw::c_int,
+            _: std::os::raw::c_int,
+            _: *const *const std::os::raw::c_char,
+        ) -> std::os::raw::c_int {
+            let code: $crate::PamError = $code;
+            code as std::os::raw::c_int
+        }
+    };
+
+    ($pamsm_ty:ty $(, $($opts:tt)*)?) => {
+        $crate::pam_module!(
+            @opts [$pamsm_ty] [<$pamsm_ty as Default>::default] [$crate::PamError::SERVICE_ERR]
+            [auth account session password] [] $(, $($opts)*)?
+        );
     };
 }
@@ -4,19 +4,22 @@
 
 use pam::PamError;
 use std::option::Option;
-use std::os::raw::{c_char, c_int, c_void};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
 use std::ptr::NonNull;
 
 pub type PamHandle = *const c_void;
 
+/// Maximum number of messages that can be passed to the conversation function at once.
+pub const PAM_MAX_NUM_MSG: usize = 32;
+
 #[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum PamMsgStyle {
     PROMPT_ECHO_OFF = 1, /* Ask for password without echo */
     PROMPT_ECHO_ON = 2,  /* Ask for password with echo */
     ERROR_MSG = 3,       /* Display an error message */
     TEXT_INFO = 4,       /* Display arbitrary text */
     // Linux extensions
-    PAM_MAX_NUM_MSG = 32,
     PAM_RADIO_TYPE = 5, /* yes/no/maybe conditionals */
     PAM_BINARY_PROMPT = 7,
 }
@@ -46,6 +49,30 @@ pub(crate) struct PamConv {
     pub(crate) appdata_ptr: *mut c_void,
 }
 
+/// X server authentication data, i.e. the PAM_XAUTHDATA item.
+/// This mirrors `struct pam_xauth_data`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PamXAuthData<'a> {
+    /// Name of the authentication method, e.g. `MIT-MAGIC-COOKIE-1`.
+    pub name: &'a [u8],
+    /// Authentication data, e.g. the cookie itself.
+    pub data: &'a [u8],
+}
+
+#[repr(C)]
+pub(crate) struct PamXAuthDataRaw {
+    pub(crate) namelen: c_int,
+    pub(crate) name: *const c_char,
+    pub(crate) datalen: c_int,
+    pub(crate) data: *const c_char,
+}
+
+/// Delay function installed by the application, i.e. the PAM_FAIL_DELAY item.
+/// It is called by pam with the result of the stack, the delay requested by the modules
+/// in microseconds, and the application data pointer of the conversation.
+pub type PamFailDelayFn =
+    unsafe extern "C" fn(retval: c_int, usec_delay: c_uint, appdata_ptr: *mut c_void);
+
 #[repr(C)]
 pub enum LogLvl {
     EMERG = 0,   /* system is unusable */
@@ -58,6 +85,7 @@ pub enum LogLvl {
     DEBUG = 7,   /* debug-level messages */
 }
 
+#[derive(Debug)]
 #[repr(C)]
 pub enum PamItemType {
     SERVICE = 1,       /* The service name */
@@ -0,0 +1,146 @@
+use std::borrow::Borrow;
+use std::ffi::{CStr, CString, NulError};
+use std::fmt;
+use std::ops::Deref;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+
+/// Borrowed secret string, such as a password or a one-time code.
+///
+/// The content isn't displayed by `Debug` and has to be accessed explicitly with
+/// [`expose`][Self::expose]. Comparisons are done in constant time.
+/// ```rust
+/// # use pamsm::Secret;
+/// let secret = Secret::new("hunter2").unwrap();
+/// assert_eq!(format!("{:?}", secret), "Secret(\"****\")");
+/// assert_eq!(secret.expose().to_bytes(), b"hunter2");
+/// assert!(*secret == *Secret::new("hunter2").unwrap());
+/// ```
+#[repr(transparent)]
+pub struct SecretStr(CStr);
+
+impl SecretStr {
+    /// Wrap a C string in a `SecretStr`.
+    pub fn new(s: &CStr) -> &SecretStr {
+        // SecretStr is a transparent wrapper around CStr
+        unsafe { &*(s as *const CStr as *const SecretStr) }
+    }
+
+    /// Access the secret content.
+    pub fn expose(&self) -> &CStr {
+        &self.0
+    }
+}
+
+impl PartialEq for SecretStr {
+    fn eq(&self, other: &SecretStr) -> bool {
+        constant_time_eq(self.0.to_bytes(), other.0.to_bytes())
+    }
+}
+
+impl Eq for SecretStr {}
+
+impl fmt::Debug for SecretStr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SecretStr(\"****\")")
+    }
+}
+
+impl ToOwned for SecretStr {
+    type Owned = Secret;
+
+    fn to_owned(&self) -> Secret {
+        Secret::from(self.expose())
+    }
+}
+
+/// Owned secret string, overwritten with zeros when dropped.
+///
+/// This derefs to [`SecretStr`], which gives access to the content.
+pub struct Secret(Box<[u8]>);
+
+impl Secret {
+    /// Create a new secret. This fails if `secret` contains a null byte.
+    ///
+    /// Note that `secret` may be reallocated to append the null terminator, in which
+    /// case the previous allocation isn't cleared. Use `Secret::from` with a `CString`
+    /// to avoid that.
+    pub fn new<T: Into<Vec<u8>>>(secret: T) -> Result<Secret, NulError> {
+        CString::new(secret).map(Secret::from)
+    }
+}
+
+impl From<CString> for Secret {
+    fn from(s: CString) -> Secret {
+        // A CString is backed by a boxed slice, this doesn't reallocate.
+        Secret(s.into_bytes_with_nul().into_boxed_slice())
+    }
+}
+
+impl<'a> From<&'a CStr> for Secret {
+    fn from(s: &'a CStr) -> Secret {
+        Secret(s.to_bytes_with_nul().into())
+    }
+}
+
+impl Deref for Secret {
+    type Target = SecretStr;
+
+    fn deref(&self) -> &SecretStr {
+        SecretStr::new(unsafe { CStr::from_bytes_with_nul_unchecked(&self.0) })
+    }
+}
+
+impl Borrow<SecretStr> for Secret {
+    fn borrow(&self) -> &SecretStr {
+        self
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Secret {
+        Secret(self.0.clone())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Secret) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Secret {}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Secret(\"****\")")
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        zeroize(&mut self.0);
+    }
+}
+
+/// Overwrite `buf` with zeros, in a way that can't be optimized away.
+pub(crate) fn zeroize(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Compare two byte strings in a time which only depends on their length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    // Prevent the compiler from short-circuiting the loop above
+    unsafe { ptr::read_volatile(&diff) == 0 }
+}
@@ -0,0 +1,167 @@
+//! Layer of `tracing-subscriber` sending the events to syslog.
+
+use entry::{debug_call, syslog_current};
+use pam_types::LogLvl;
+use std::fmt::{self, Write};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::subscriber::{Interest, SetGlobalDefaultError};
+use tracing::{Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+/// [`tracing_subscriber`] layer sending the events to syslog.
+///
+/// Events are prefixed with their spans and fields. During a call to the module they are sent
+/// with `pam_syslog`, with syslog(3) otherwise.
+/// `pam_module!` opens a `pam` span for each call, with the exported function name,
+/// and the service, user and rhost items.
+///
+/// Events up to the level set with [`with_max_level`][Self::with_max_level] are logged,
+/// `INFO` by default. When the module is given the `debug` argument, `DEBUG` events are logged
+/// during its calls.
+/// ```rust
+/// # extern crate pamsm;
+/// # extern crate tracing;
+/// # use pamsm::PamSyslogLayer;
+/// # fn main() {
+/// PamSyslogLayer::new().install().ok();
+/// tracing::info!(retries = 3, "module loaded");
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct PamSyslogLayer {
+    max_level: Level,
+}
+
+impl PamSyslogLayer {
+    pub fn new() -> PamSyslogLayer {
+        PamSyslogLayer {
+            max_level: Level::INFO,
+        }
+    }
+
+    /// Set the maximum level of the events to log.
+    pub fn with_max_level(self, max_level: Level) -> PamSyslogLayer {
+        PamSyslogLayer { max_level }
+    }
+
+    /// Set a subscriber made of this layer as the global default.
+    pub fn install(self) -> Result<(), SetGlobalDefaultError> {
+        tracing::subscriber::set_global_default(Registry::default().with(self))
+    }
+
+    fn max_level(&self) -> Level {
+        if debug_call() {
+            self.max_level.max(Level::DEBUG)
+        } else {
+            self.max_level
+        }
+    }
+}
+
+impl Default for PamSyslogLayer {
+    fn default() -> PamSyslogLayer {
+        PamSyslogLayer::new()
+    }
+}
+
+// Formatted fields of a span
+struct SpanFields(String);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for PamSyslogLayer {
+    fn register_callsite(&self, _: &'static Metadata<'static>) -> Interest {
+        // The level filter depends on the current call
+        Interest::sometimes()
+    }
+
+    fn enabled(&self, metadata: &Metadata, _: Context<S>) -> bool {
+        // Spans are kept for the events they contain
+        metadata.is_span() || *metadata.level() <= self.max_level()
+    }
+
+    fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut visitor = FieldsVisitor::default();
+            attrs.record(&mut visitor);
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(fields) = extensions.get_mut::<SpanFields>() {
+                let mut visitor = FieldsVisitor {
+                    message: String::new(),
+                    fields: std::mem::take(&mut fields.0),
+                };
+                values.record(&mut visitor);
+                fields.0 = visitor.fields;
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let mut msg = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                msg.push_str(span.name());
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    if !fields.0.is_empty() {
+                        let _ = write!(msg, "{{{}}}", fields.0);
+                    }
+                }
+                msg.push_str(": ");
+            }
+        }
+
+        let mut visitor = FieldsVisitor::default();
+        event.record(&mut visitor);
+        msg.push_str(&visitor.message);
+        if !visitor.fields.is_empty() {
+            if !visitor.message.is_empty() {
+                msg.push(' ');
+            }
+            msg.push_str(&visitor.fields);
+        }
+
+        let lvl = match *event.metadata().level() {
+            Level::ERROR => LogLvl::ERR,
+            Level::WARN => LogLvl::WARNING,
+            Level::INFO => LogLvl::INFO,
+            _ => LogLvl::DEBUG,
+        };
+        syslog_current(lvl, &msg);
+    }
+}
+
+// Formats the message and the other fields as `name=value`
+#[derive(Default)]
+struct FieldsVisitor {
+    message: String,
+    fields: String,
+}
+
+impl FieldsVisitor {
+    fn record(&mut self, field: &Field, value: fmt::Arguments) {
+        if field.name() == "message" {
+            let _ = self.message.write_fmt(value);
+        } else {
+            if !self.fields.is_empty() {
+                self.fields.push(' ');
+            }
+            let _ = write!(self.fields, "{}={}", field.name(), value);
+        }
+    }
+}
+
+impl Visit for FieldsVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, format_args!("{}", value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format_args!("{:?}", value));
+    }
+}
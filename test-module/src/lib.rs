@@ -2,19 +2,24 @@
 extern crate pamsm;
 extern crate rand;
 
-use pamsm::{LogLvl, Pam, PamData, PamError, PamFlags, PamLibExt, PamServiceModule};
+use pamsm::{
+    AuthFlags, LogLvl, Pam, PamData, PamError, PamFlags, PamLibExt, PamModuleResult, PamResultExt,
+    PamResultServiceModule, SessionFlags,
+};
 use rand::RngCore;
+use std::ffi::CStr;
 use std::fs::write;
 use std::time::Instant;
 
+#[derive(Default)]
 struct PamTime;
 
 #[derive(Debug, Clone)]
 struct SessionStart(Instant);
 
 impl PamData for SessionStart {
-    fn cleanup(&self, _pam: Pam, flags: PamFlags, status: PamError) {
-        if !flags.contains(PamFlags::SILENT) {
+    fn cleanup(&self, _pam: &Pam, flags: PamFlags, status: PamError) {
+        if !flags.contains(PamFlags::DATA_SILENT) {
             println!(
                 "PamTime cleanup. Session opened for {:?}, result {}, flags {:?}",
                 self.0.elapsed(),
@@ -28,65 +33,61 @@ impl PamData for SessionStart {
     }
 }
 
-impl PamServiceModule for PamTime {
-    fn open_session(pamh: Pam, _flags: PamFlags, _args: Vec<String>) -> PamError {
-        pamh.syslog(LogLvl::WARNING, "hehe coucou %s %s").expect("Failed to send syslog");
+impl PamResultServiceModule for PamTime {
+    fn open_session(
+        &self,
+        pamh: &mut Pam,
+        _flags: SessionFlags,
+        _args: &[&CStr],
+    ) -> PamModuleResult<()> {
+        pamh.syslog(LogLvl::WARNING, "hehe coucou %s %s")?;
         let now = SessionStart(Instant::now());
-        if let Err(e) = unsafe { pamh.send_data("pamtime", now) } {
-            return e;
-        }
+        pamh.send_data("pamtime", now)?;
 
         let mut token = vec![0u8; 32];
         rand::thread_rng().fill_bytes(&mut token);
-        let res = pamh.send_bytes(
+        pamh.send_bytes(
             "pamtime_token",
             token,
-            Some(|token, _, _, _| {
+            Some(Box::new(|token, _, _, _| {
                 if let Err(e) = write(".token.bin", token) {
                     println!("Error persisting token : {:?}", e);
                 }
-            }),
-        );
-
-        if let Err(e) = res {
-            return e;
-        }
-
-        PamError::SUCCESS
+            })),
+        )
+        .context("storing the session token")
     }
 
-    fn close_session(_pamh: Pam, _flags: PamFlags, _args: Vec<String>) -> PamError {
-        PamError::SUCCESS
+    fn close_session(
+        &self,
+        _pamh: &mut Pam,
+        _flags: SessionFlags,
+        _args: &[&CStr],
+    ) -> PamModuleResult<()> {
+        Ok(())
     }
 
-    fn authenticate(pamh: Pam, _flags: PamFlags, _args: Vec<String>) -> PamError {
+    fn authenticate(
+        &self,
+        pamh: &mut Pam,
+        _flags: AuthFlags,
+        _args: &[&CStr],
+    ) -> PamModuleResult<()> {
         // If you need password here, that works like this:
         //
-        //  let pass = match pamh.get_authtok(None) {
-        //      Ok(Some(p)) => p,
-        //      Ok(None) => return PamError::AUTH_ERR,
-        //      Err(e) => return e,
-        //  };
+        //  let pass = pamh.get_authtok(None)?.ok_or(PamError::AUTH_ERR)?;
 
         // Only allow authentication when user name is root and the session is less than a minute
         // old
-        let user = match pamh.get_user(None) {
-            Ok(Some(u)) => u,
-            Ok(None) => return PamError::USER_UNKNOWN,
-            Err(e) => return e,
-        };
-
-        let s: SessionStart = match unsafe { pamh.retrieve_data("pamtime") } {
-            Err(e) => return e,
-            Ok(tref) => tref,
-        };
+        let user = pamh.get_user(None)?.ok_or(PamError::USER_UNKNOWN)?;
+        let s: SessionStart = pamh.retrieve_data("pamtime")?;
 
         if user.to_str().unwrap_or("") == "root" && s.0.elapsed().as_secs() < 60 {
-            PamError::SUCCESS
+            Ok(())
         } else {
-            PamError::AUTH_ERR
+            Err(PamError::AUTH_ERR.into())
         }
     }
 }
 
-pam_module!(PamTime);
+pam_module!(PamTime, export = [auth, session]);